@@ -11,7 +11,6 @@ use embedded_graphics::{
 use linux_embedded_hal::Delay;
 
 use ssd1306::{prelude::*, Ssd1306};
-use embedded_hal_compat::{ReverseCompat as _};
 
 fn main() {
     // Find matching devices
@@ -23,20 +22,20 @@ fn main() {
     let spi = cp2130.spi(1, SpiConfig::default()).unwrap();
 
     let dc = cp2130
-        .gpio_out(0, GpioMode::PushPull, GpioLevel::Low)
+        .gpio_out(GpioPin::Gpio0, GpioMode::PushPull, GpioLevel::Low)
         .unwrap();
 
-    let rst = cp2130
-        .gpio_out(1, GpioMode::PushPull, GpioLevel::Low)
+    let mut rst = cp2130
+        .gpio_out(GpioPin::Gpio1, GpioMode::PushPull, GpioLevel::Low)
         .unwrap();
 
     let mut delay = Delay {};
 
-    let interface = SPIInterfaceNoCS::new(spi.reverse(), dc.reverse());
+    let interface = SPIInterfaceNoCS::new(spi, dc);
     let mut disp = Ssd1306::new(interface, DisplaySize128x64, DisplayRotation::Rotate0)
         .into_buffered_graphics_mode();
 
-    disp.reset(&mut rst.reverse(), &mut delay).unwrap();
+    disp.reset(&mut rst, &mut delay).unwrap();
     disp.init().unwrap();
 
     let text_style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
@@ -51,5 +50,7 @@ fn main() {
 
     disp.flush().unwrap();
 
-    loop {}
+    loop {
+        std::thread::sleep(std::time::Duration::from_secs(1));
+    }
 }
@@ -0,0 +1,682 @@
+//! CP2130 async API, implementing the embedded-hal-async traits
+//!
+//! Each device owns an event thread (started on first use) which starts queued SPI
+//! commands on the bulk transfer engine and handles libusb events until they finish.
+//! Futures are completed from the transfer callbacks, so async executors are never
+//! blocked on USB I/O. Control requests (such as GPIO reads) and transactions
+//! including delays are executed as blocking calls on the event thread.
+//!
+//! Copyright 2019 Ryan Kurte
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Weak, Mutex, PoisonError, mpsc};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use embedded_hal::spi::Operation;
+
+use crate::{Cp2130, Spi, SpiDevice, InputPin, Error, lock};
+use crate::device::{Inner, PendingSpi};
+
+/// Default polling interval for async GPIO waits
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Queued device operation, returning any command started on the transfer engine
+type Job = Box<dyn FnOnce(Result<&mut Inner, Error>) -> Option<PendingSpi> + Send>;
+
+lazy_static!{
+    /// Timer shared by all async handles
+    static ref TIMER: Mutex<Timer> = Mutex::new(Timer::new());
+}
+
+/// Handle to a device event thread
+#[derive(Clone)]
+pub(crate) struct Events {
+    tx: mpsc::Sender<Job>,
+}
+
+impl Events {
+    /// Fetch the event thread for a device, starting it on first use
+    fn for_device(inner: &Arc<Mutex<Inner>>) -> Self {
+        // Failures (including a poisoned lock) are reported by each queued operation
+        let mut i = inner.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(e) = &i.events {
+            return e.clone()
+        }
+
+        let (tx, rx) = mpsc::channel::<Job>();
+        let device = Arc::downgrade(inner);
+
+        std::thread::Builder::new().name("cp2130-events".to_string())
+            .spawn(move || Self::run_loop(device, rx))
+            .expect("failed to spawn event thread");
+
+        let events = Self{tx};
+        i.events = Some(events.clone());
+
+        events
+    }
+
+    /// Execute queued operations in order, exiting once the device and all handles are dropped
+    fn run_loop(device: Weak<Mutex<Inner>>, rx: mpsc::Receiver<Job>) {
+        for job in rx {
+            let device = match device.upgrade() {
+                Some(d) => d,
+                None => {
+                    job(Err(Error::Disconnected(crate::Op::Device)));
+                    continue
+                },
+            };
+
+            let mut inner = match lock(&device) {
+                Ok(i) => i,
+                Err(e) => {
+                    job(Err(e));
+                    continue
+                },
+            };
+
+            if let Some(p) = job(Ok(&mut inner)) {
+                inner.spi_finish(p);
+            }
+        }
+    }
+
+    /// Queue a blocking operation, returning a future that resolves on completion
+    fn run<T, F>(&self, f: F) -> Completion<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Inner) -> Result<T, Error> + Send + 'static,
+    {
+        self.start(move |inner, c| {
+            c.complete(f(inner));
+            None
+        })
+    }
+
+    /// Queue an operation which may start an SPI command, returning a future that
+    /// resolves when the operation calls the provided completer
+    fn start<T, F>(&self, f: F) -> Completion<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Inner, Completer<T>) -> Option<PendingSpi> + Send + 'static,
+    {
+        let state = Arc::new(Mutex::new(State{value: None, waker: None}));
+        let c = Completer{state: state.clone()};
+
+        // On failure the job (and completer) is dropped, resolving the future with an error
+        let _ = self.tx.send(Box::new(move |inner: Result<&mut Inner, Error>| match inner {
+            Ok(inner) => f(inner, c),
+            Err(e) => {
+                c.complete(Err(e));
+                None
+            },
+        }));
+
+        Completion{state}
+    }
+}
+
+struct State<T> {
+    value: Option<Result<T, Error>>,
+    waker: Option<Waker>,
+}
+
+/// Completes a queued operation
+struct Completer<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl <T> Completer<T> {
+    fn complete(self, v: Result<T, Error>) {
+        let mut s = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        s.value = Some(v);
+        if let Some(w) = s.waker.take() {
+            w.wake();
+        }
+    }
+}
+
+impl <T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        // Resolve dropped operations (ie. if the event thread has exited)
+        if let Ok(mut s) = self.state.lock() {
+            if s.value.is_none() {
+                s.value = Some(Err(Error::Disconnected(crate::Op::Device)));
+                if let Some(w) = s.waker.take() {
+                    w.wake();
+                }
+            }
+        }
+    }
+}
+
+/// Future resolving when a queued operation completes
+struct Completion<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl <T> Future for Completion<T> {
+    type Output = Result<T, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut s = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        match s.value.take() {
+            Some(v) => Poll::Ready(v),
+            None => {
+                s.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Background timer, waking tasks at their deadlines
+#[derive(Clone)]
+struct Timer {
+    tx: mpsc::Sender<(Instant, Waker)>,
+}
+
+impl Timer {
+    fn new() -> Self {
+        let (tx, rx) = mpsc::channel::<(Instant, Waker)>();
+
+        std::thread::spawn(move || {
+            let mut pending: Vec<(Instant, Waker)> = vec![];
+
+            loop {
+                // Wait for new timers or the next deadline
+                let next = pending.iter().map(|(t, _w)| *t).min();
+                let r = match next {
+                    Some(t) => rx.recv_timeout(t.saturating_duration_since(Instant::now())),
+                    None => rx.recv().map_err(|_e| mpsc::RecvTimeoutError::Disconnected),
+                };
+
+                match r {
+                    Ok(v) => pending.push(v),
+                    Err(mpsc::RecvTimeoutError::Timeout) => (),
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+
+                // Wake expired timers
+                let now = Instant::now();
+                pending.retain(|(t, w)| {
+                    if *t <= now {
+                        w.wake_by_ref();
+                    }
+                    *t > now
+                });
+            }
+        });
+
+        Self{tx}
+    }
+
+    /// Fetch the shared timer
+    fn shared() -> Self {
+        TIMER.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    fn sleep(&self, d: Duration) -> Sleep {
+        Sleep{deadline: Instant::now() + d, timer: self.clone()}
+    }
+}
+
+/// Future resolving after a deadline
+struct Sleep {
+    deadline: Instant,
+    timer: Timer,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(())
+        }
+
+        // Fall back to an immediate wake if the timer is unavailable
+        if self.timer.tx.send((self.deadline, cx.waker().clone())).is_err() {
+            cx.waker().wake_by_ref();
+        }
+
+        Poll::Pending
+    }
+}
+
+/// Async CP2130 handle, queueing operations to the device event thread
+#[derive(Clone)]
+pub struct AsyncCp2130 {
+    events: Events,
+}
+
+impl Cp2130 {
+    /// Create an async handle for the device, sharing the device event thread
+    pub fn to_async(&self) -> AsyncCp2130 {
+        AsyncCp2130{events: Events::for_device(&self.inner)}
+    }
+}
+
+impl AsyncCp2130 {
+    /// Create an async SPI connector from a blocking SPI connector
+    pub fn spi(&self, spi: Spi) -> AsyncSpi {
+        AsyncSpi{spi, events: self.events.clone()}
+    }
+
+    /// Create an async SPI device from a blocking SPI device
+    pub fn spi_device(&self, device: SpiDevice) -> AsyncSpiDevice {
+        AsyncSpiDevice{device, events: self.events.clone()}
+    }
+
+    /// Create an async input pin from a blocking input pin
+    pub fn input(&self, pin: InputPin) -> AsyncInputPin {
+        AsyncInputPin{pin, interval: DEFAULT_POLL_INTERVAL, events: self.events.clone(), timer: Timer::shared()}
+    }
+
+    /// Create an async delay provider
+    pub fn delay(&self) -> AsyncDelay {
+        AsyncDelay::new()
+    }
+}
+
+/// Owned SPI operation for blocking execution on the event thread
+enum Op {
+    Read(Vec<u8>),
+    Write(Vec<u8>),
+    Transfer(Vec<u8>, Vec<u8>),
+    TransferInPlace(Vec<u8>),
+    DelayNs(u32),
+}
+
+impl Op {
+    fn from_operation(o: &Operation<'_, u8>) -> Self {
+        match o {
+            Operation::Read(r) => Op::Read(vec![0u8; r.len()]),
+            Operation::Write(w) => Op::Write(w.to_vec()),
+            Operation::Transfer(r, w) => Op::Transfer(vec![0u8; r.len()], w.to_vec()),
+            Operation::TransferInPlace(b) => Op::TransferInPlace(b.to_vec()),
+            Operation::DelayNs(ns) => Op::DelayNs(*ns),
+        }
+    }
+
+    fn as_operation(&mut self) -> Operation<'_, u8> {
+        match self {
+            Op::Read(r) => Operation::Read(r),
+            Op::Write(w) => Operation::Write(w),
+            Op::Transfer(r, w) => Operation::Transfer(r, w),
+            Op::TransferInPlace(b) => Operation::TransferInPlace(b),
+            Op::DelayNs(ns) => Operation::DelayNs(*ns),
+        }
+    }
+}
+
+/// Async SPI connector, implementing embedded-hal-async `SpiBus`
+pub struct AsyncSpi {
+    spi: Spi,
+    events: Events,
+}
+
+impl AsyncSpi {
+    /// Fetch the underlying blocking SPI connector
+    pub fn into_inner(self) -> Spi {
+        self.spi
+    }
+
+    /// Start a single command on the event thread, returning `n` bytes of read data
+    fn exec(&self, write: Vec<u8>, n: usize) -> Completion<Vec<u8>> {
+        let (channel, config) = (self.spi.channel, self.spi.config.clone());
+
+        self.events.start(move |inner, c| inner.spi_start(channel, &config, write, n, Box::new(move |r| c.complete(r))))
+    }
+}
+
+impl embedded_hal::spi::ErrorType for AsyncSpi {
+    type Error = Error;
+}
+
+impl embedded_hal_async::spi::SpiBus<u8> for AsyncSpi {
+    async fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        let r = self.exec(vec![], words.len()).await?;
+        words.copy_from_slice(&r);
+        Ok(())
+    }
+
+    async fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        self.exec(words.to_vec(), 0).await?;
+        Ok(())
+    }
+
+    async fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+        let r = self.exec(write.to_vec(), read.len()).await?;
+        read.copy_from_slice(&r);
+        Ok(())
+    }
+
+    async fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        let r = self.exec(words.to_vec(), words.len()).await?;
+        words.copy_from_slice(&r);
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.events.run(|inner| { inner.pacer.wait(); Ok(()) }).await
+    }
+}
+
+/// Async SPI device, implementing embedded-hal-async `SpiDevice`
+/// with the hardware chip select asserted across each transaction
+pub struct AsyncSpiDevice {
+    device: SpiDevice,
+    events: Events,
+}
+
+impl AsyncSpiDevice {
+    /// Fetch the underlying blocking SPI device
+    pub fn into_inner(self) -> SpiDevice {
+        self.device
+    }
+
+    /// Execute a transaction including delays as a blocking call on the event thread
+    async fn transaction_gpio(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Error> {
+        let mut ops: Vec<_> = operations.iter().map(Op::from_operation).collect();
+        let (channel, config) = (self.device.channel(), self.device.config().clone());
+
+        let res = self.events.run(move |inner| {
+            let mut o: Vec<_> = ops.iter_mut().map(Op::as_operation).collect();
+            inner.spi_transaction(channel, &config, &mut o)?;
+            Ok(ops)
+        }).await?;
+
+        // Copy read data back to the caller's buffers
+        for (o, r) in operations.iter_mut().zip(res.iter()) {
+            match (o, r) {
+                (Operation::Read(b), Op::Read(r)) | (Operation::Transfer(b, _), Op::Transfer(r, _))
+                    | (Operation::TransferInPlace(b), Op::TransferInPlace(r)) => b.copy_from_slice(r),
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl embedded_hal::spi::ErrorType for AsyncSpiDevice {
+    type Error = Error;
+}
+
+/// Operations are merged into a single command as per the blocking `SpiDevice::transaction`
+impl embedded_hal_async::spi::SpiDevice<u8> for AsyncSpiDevice {
+    async fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Self::Error> {
+        if operations.iter().any(|o| matches!(o, Operation::DelayNs(_))) {
+            return self.transaction_gpio(operations).await
+        }
+
+        let (channel, config) = (self.device.channel(), self.device.config().clone());
+        let (out, read) = Inner::merge_operations(operations);
+        let in_len = if read { out.len() } else { 0 };
+
+        let buff = self.events.start(move |inner, c| {
+            if out.is_empty() {
+                c.complete(inner.spi_select(channel, &config).map(|_| vec![]));
+                return None
+            }
+
+            inner.spi_start(channel, &config, out, in_len, Box::new(move |r| c.complete(r)))
+        }).await?;
+
+        if read {
+            Inner::split_operations(operations, &buff);
+        }
+
+        Ok(())
+    }
+}
+
+/// Async input pin, implementing embedded-hal-async `Wait` by polling the pin level
+pub struct AsyncInputPin {
+    pin: InputPin,
+    interval: Duration,
+    events: Events,
+    timer: Timer,
+}
+
+impl AsyncInputPin {
+    /// Set the interval for polling the pin level
+    pub fn set_poll_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    /// Fetch the underlying blocking input pin
+    pub fn into_inner(self) -> InputPin {
+        self.pin
+    }
+
+    /// Read the current pin level
+    pub async fn is_high(&mut self) -> Result<bool, Error> {
+        let index = self.pin.pin().index();
+        self.events.run(move |inner| inner.get_gpio_level(index)).await
+    }
+
+    /// Poll until the level matches
+    async fn wait_level(&mut self, high: bool) -> Result<(), Error> {
+        while self.is_high().await? != high {
+            self.timer.sleep(self.interval).await;
+        }
+        Ok(())
+    }
+
+    /// Poll until the level changes, optionally to a specific level
+    async fn wait_edge(&mut self, to: Option<bool>) -> Result<(), Error> {
+        let mut last = self.is_high().await?;
+        loop {
+            self.timer.sleep(self.interval).await;
+
+            let v = self.is_high().await?;
+            if v != last && to.map(|t| t == v).unwrap_or(true) {
+                return Ok(())
+            }
+            last = v;
+        }
+    }
+}
+
+impl embedded_hal::digital::ErrorType for AsyncInputPin {
+    type Error = Error;
+}
+
+impl embedded_hal_async::digital::Wait for AsyncInputPin {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        self.wait_level(true).await
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        self.wait_level(false).await
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_edge(Some(true)).await
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_edge(Some(false)).await
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_edge(None).await
+    }
+}
+
+/// Async delay provider, implementing embedded-hal-async `DelayNs` without blocking the executor
+#[derive(Clone)]
+pub struct AsyncDelay {
+    timer: Timer,
+}
+
+impl AsyncDelay {
+    /// Create an async delay provider, sharing the background timer thread
+    pub fn new() -> Self {
+        Self{timer: Timer::shared()}
+    }
+}
+
+impl Default for AsyncDelay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl embedded_hal_async::delay::DelayNs for AsyncDelay {
+    async fn delay_ns(&mut self, ns: u32) {
+        self.timer.sleep(Duration::from_nanos(ns as u64)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::task::Wake;
+    use std::thread::{self, Thread};
+
+    use embedded_hal::spi::Operation;
+    use embedded_hal_async::delay::DelayNs;
+    use embedded_hal_async::spi::{SpiBus, SpiDevice as _};
+    use rusb::Direction;
+
+    use super::*;
+    use crate::{SpiConfig, ErrorKind};
+    use crate::transport::fake::{FakeTransport, Request, BulkFault};
+
+    /// Minimal executor, parking the thread until woken
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(f: F) -> F::Output {
+        let mut f = std::pin::pin!(f);
+        let waker = Arc::new(ThreadWaker(thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+
+        loop {
+            match f.as_mut().poll(&mut cx) {
+                Poll::Ready(v) => return v,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    /// Bulk commands issued, with the data following the command header
+    fn bulk_requests(t: &FakeTransport) -> Vec<(Vec<u8>, usize)> {
+        t.take_requests().into_iter().filter_map(|r| match r {
+            Request::Bulk(out, in_len) => Some((out[8..].to_vec(), in_len)),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn async_delay() {
+        let mut d = AsyncDelay::new();
+
+        let start = Instant::now();
+        block_on(d.delay_ms(20));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        // Concurrent delays share the timer thread
+        let mut d2 = d.clone();
+        let start = Instant::now();
+        block_on(async {
+            d.delay_ms(10).await;
+            d2.delay_us(5_000).await;
+        });
+        assert!(start.elapsed() >= Duration::from_millis(15));
+    }
+
+    #[test]
+    fn event_thread_shared() {
+        let (d, t) = crate::tests::fake();
+        assert!(d.inner.lock().unwrap().events.is_none());
+
+        // The event thread is started on first use and owned by the device
+        let (a, b) = (d.to_async(), d.to_async());
+        assert!(d.inner.lock().unwrap().events.is_some());
+
+        // Operations from each handle are executed in order
+        let mut s1 = a.spi(d.spi(1, SpiConfig::default()).unwrap());
+        let mut s2 = b.spi(d.spi(1, SpiConfig::default()).unwrap());
+        t.take_requests();
+        block_on(async {
+            s1.write(&[1]).await.unwrap();
+            s2.write(&[2]).await.unwrap();
+        });
+        assert_eq!(bulk_requests(&t), vec![(vec![1], 0), (vec![2], 0)]);
+    }
+
+    #[test]
+    fn spi_commands_complete_from_callback() {
+        let (d, t) = crate::tests::fake();
+        let mut spi = d.to_async().spi(d.spi(1, SpiConfig::default()).unwrap());
+        t.take_requests();
+
+        // The fake loops back written data, completing on the next event
+        let mut buff = [0u8; 3];
+        block_on(spi.transfer(&mut buff, &[1, 2, 3])).unwrap();
+        assert_eq!(buff, [1, 2, 3]);
+        assert_eq!(bulk_requests(&t), vec![(vec![1, 2, 3], 3)]);
+
+        // Excess read data is discarded, as for blocking transfers
+        let mut buff = [0u8; 1];
+        block_on(spi.transfer(&mut buff, &[4, 5])).unwrap();
+        assert_eq!(buff, [4]);
+        assert_eq!(bulk_requests(&t), vec![(vec![4, 5], 2)]);
+
+        block_on(spi.write(&[6, 7])).unwrap();
+        assert_eq!(bulk_requests(&t), vec![(vec![6, 7], 0)]);
+    }
+
+    #[test]
+    fn spi_failure_resyncs() {
+        let (d, t) = crate::tests::fake();
+        let mut spi = d.to_async().spi(d.spi(1, SpiConfig::default()).unwrap());
+        t.take_requests();
+
+        // The command is written but the read fails
+        t.push_bulk(Some(BulkFault{written: 8 + 2, read: 0, error: rusb::Error::Timeout}));
+
+        let mut buff = [0u8; 2];
+        let e = block_on(spi.transfer_in_place(&mut buff)).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Timeout);
+
+        // The device is re-synchronised before the next operation
+        let requests = t.take_requests();
+        assert!(requests.contains(&Request::ClearHalt(Direction::In)));
+
+        block_on(spi.write(&[1])).unwrap();
+        assert_eq!(bulk_requests(&t), vec![(vec![1], 0)]);
+    }
+
+    #[test]
+    fn spi_transaction_merges_operations() {
+        let (d, t) = crate::tests::fake();
+        let mut dev = d.to_async().spi_device(d.spi_device(1, SpiConfig::default()).unwrap());
+        t.take_requests();
+
+        let mut read = [0xFFu8; 2];
+        let mut in_place = [7u8, 8];
+        let mut ops = [
+            Operation::Write(&[1, 2]),
+            Operation::Read(&mut read),
+            Operation::TransferInPlace(&mut in_place),
+        ];
+        block_on(dev.transaction(&mut ops)).unwrap();
+
+        // Operations are issued as a single command, with read data split back out
+        assert_eq!(bulk_requests(&t), vec![(vec![1, 2, 0, 0, 7, 8], 6)]);
+        assert_eq!(read, [0, 0]);
+        assert_eq!(in_place, [7, 8]);
+    }
+}
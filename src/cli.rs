@@ -3,6 +3,9 @@
 //! 
 //! Copyright 2019 Ryan Kurte
 
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
 extern crate structopt;
 use structopt::StructOpt;
 
@@ -13,7 +16,7 @@ use simplelog::{TermLogger, LevelFilter, TerminalMode};
 use driver_cp2130::prelude::*;
 
 extern crate embedded_hal;
-use embedded_hal::spi::blocking::*;
+use embedded_hal::spi::Operation;
 
 extern crate hex;
 extern crate rand;
@@ -49,19 +52,17 @@ pub enum Command {
     Version,
     /// Fetch chip info
     Info,
-    /// Set a GPIO output
+    /// Fetch a snapshot of the device state
+    Status,
+    /// Set one or more GPIO outputs in a single update
     SetOutput {
-        #[structopt(long, default_value="6")]
-        /// GPIO pin index
-        pin: u8,
+        #[structopt(parse(try_from_str = parse_pin_level), default_value="6=high")]
+        /// GPIO pin index and state pairs (eg. 6=high 7=low)
+        pins: Vec<(u8, GpioLevel)>,
 
         #[structopt(long, default_value="push-pull")]
         /// GPIO pin mode to set (input, open drain, push-pull)
         mode: GpioMode,
-
-        #[structopt(default_value="high")]
-        /// GPIO pin state (high, low)
-        state: GpioLevel,
     },
     /// Read a GPIO input
     ReadInput {
@@ -73,6 +74,30 @@ pub enum Command {
         /// GPIO pin mode to set
         mode: Option<GpioMode>,
     },
+    /// Fetch or set the read FIFO full threshold
+    FullThreshold {
+        /// New threshold (1 to 255), if not specified the current threshold is reported
+        threshold: Option<u8>,
+    },
+    /// Configure the CLKOUT clock generator on GPIO5
+    ClockOut {
+        /// Target output frequency in Hz, if not specified the current frequency is reported
+        frequency: Option<u32>,
+    },
+    /// Measure the event rate on GPIO4 using the event counter
+    EventCount {
+        #[structopt(long, default_value="rising")]
+        /// Event counter mode (rising, falling, negative-pulse, positive-pulse)
+        mode: EventCounterMode,
+
+        #[structopt(long, default_value="1000")]
+        /// Sampling window in milliseconds
+        window_ms: u64,
+
+        #[structopt(long)]
+        /// Events per revolution, for reporting RPM
+        events_per_rev: Option<u32>,
+    },
     /// Transfer (write-read) to an attached SPI device
     SpiTransfer {
         #[structopt(parse(try_from_str=parse_hex_str))]
@@ -91,6 +116,130 @@ pub enum Command {
         #[structopt(flatten)]
         spi_opts: SpiOpts,
     },
+    /// Fetch the USB configuration
+    UsbConfig,
+    /// Set USB configuration fields, unspecified fields are left unchanged
+    ///
+    /// WARNING: this memory is one-time-programmable
+    SetUsbConfig {
+        #[structopt(long, parse(try_from_str=parse_hex_u16))]
+        /// USB Vendor ID (VID) in hex
+        vid: Option<u16>,
+
+        #[structopt(long, parse(try_from_str=parse_hex_u16))]
+        /// USB Product ID (PID) in hex
+        pid: Option<u16>,
+
+        #[structopt(long, parse(try_from_str=parse_max_power))]
+        /// Maximum bus current in mA (up to 510 mA)
+        max_power: Option<u16>,
+
+        #[structopt(long)]
+        /// Power mode (bus, self, self-regulator)
+        power_mode: Option<PowerMode>,
+
+        #[structopt(long, parse(try_from_str=parse_hex_u16))]
+        /// Release version in hex (major, minor), eg. 0102 for 1.2
+        release_version: Option<u16>,
+
+        #[structopt(long)]
+        /// Transfer priority (read, write)
+        transfer_priority: Option<TransferPriority>,
+    },
+    /// Fetch a programmable USB string
+    GetString {
+        /// String to fetch (manufacturer, product, serial)
+        field: UsbString,
+    },
+    /// Set a programmable USB string
+    ///
+    /// WARNING: this memory is one-time-programmable
+    SetString {
+        /// String to set (manufacturer, product, serial)
+        field: UsbString,
+
+        /// New string value
+        value: String,
+    },
+    /// Fetch the pin configuration
+    PinConfig,
+    /// Set the function for a GPIO pin in the pin configuration
+    ///
+    /// WARNING: this memory is one-time-programmable
+    SetPinFunction {
+        #[structopt(long)]
+        /// GPIO pin index
+        pin: u8,
+
+        /// Pin function (input, open-drain, push-pull, chip-select, n-rtr, rtr, event-rising, event-falling,
+        /// event-negative-pulse, event-positive-pulse, clock-out, spi-activity, suspend, n-suspend)
+        function: PinFunction,
+    },
+    /// Fetch the lock byte, showing which OTP fields are still writable
+    LockStatus,
+    /// Permanently lock OTP fields
+    ///
+    /// WARNING: this is irreversible
+    Lock {
+        #[structopt(parse(try_from_str=parse_lock_fields))]
+        /// Comma separated fields to lock (vid, pid, max-power, power-mode, release-version,
+        /// transfer-priority, manufacturer, product, serial, pin-config, all)
+        fields: LockByte,
+
+        #[structopt(long)]
+        /// Confirm that locking is irreversible
+        confirm: bool,
+    },
+    /// Read the PROM (OTP configuration) image to a file
+    PromRead {
+        /// File to write the PROM image to
+        file: PathBuf,
+    },
+    /// Write a PROM (OTP configuration) image from a file
+    ///
+    /// WARNING: this memory is one-time-programmable
+    PromWrite {
+        /// File to read the PROM image from
+        file: PathBuf,
+
+        #[structopt(long)]
+        /// New file to save the existing PROM image to before writing
+        /// (defaults to cp2130-prom-backup-SERIAL.bin)
+        backup: Option<PathBuf>,
+    },
+    /// Read from an attached SPI device using ready-to-read (RTR) flow control
+    SpiReadRtr {
+        /// Number of bytes to read
+        len: usize,
+
+        #[structopt(long, default_value="1000")]
+        /// Read timeout in milliseconds
+        timeout_ms: u64,
+
+        #[structopt(flatten)]
+        spi_opts: SpiOpts,
+    },
+    /// Watch GPIO pins for changes, optionally capturing to a VCD file
+    Watch {
+        /// GPIO pins to watch (defaults to all pins)
+        pins: Vec<GpioPin>,
+
+        #[structopt(long, default_value="10")]
+        /// Sampling interval in milliseconds
+        interval_ms: u64,
+
+        #[structopt(long, default_value="1")]
+        /// Consecutive samples required to accept a change (1 disables debouncing)
+        debounce: u32,
+
+        #[structopt(long)]
+        /// Watch duration in milliseconds (runs until interrupted if unset)
+        duration_ms: Option<u64>,
+
+        #[structopt(long)]
+        /// VCD file to write captured changes to
+        vcd: Option<PathBuf>,
+    },
     /// Test interaction with the CP2130 device
     Test(TestOpts)
 }
@@ -101,9 +250,9 @@ pub struct SpiOpts {
     /// SPI Channel
     channel: u8,
 
-    #[structopt(long, default_value="0")]
-    /// SPI CS gpio index
-    cs_pin: u8,
+    #[structopt(long)]
+    /// SPI CS gpio index, driven manually (uses the channel hardware chip select if unset)
+    cs_pin: Option<u8>,
 }
 
 #[derive(Debug, StructOpt)]
@@ -115,6 +264,10 @@ pub struct TestOpts {
     #[structopt(long, default_value="1")]
     /// Pin for GPIO read
     read_pin: u8,
+
+    #[structopt(long, default_value="4096")]
+    /// Length for the extended SPI transfer test
+    transfer_len: usize,
 }
 
 type Data = Vec<u8>;
@@ -123,6 +276,52 @@ fn parse_hex_str(src: &str) -> Result<Vec<u8>, hex::FromHexError> {
     hex::decode(src)
 }
 
+fn parse_lock_fields(src: &str) -> Result<LockByte, String> {
+    let mut fields = LockByte::empty();
+
+    for f in src.split(',') {
+        fields |= match f.trim() {
+            "vid" => LockByte::VID,
+            "pid" => LockByte::PID,
+            "max-power" => LockByte::MAX_POWER,
+            "power-mode" => LockByte::POWER_MODE,
+            "release-version" => LockByte::RELEASE_VERSION,
+            "transfer-priority" => LockByte::TRANSFER_PRIORITY,
+            "manufacturer" => LockByte::MANUFACTURER_STRING_1 | LockByte::MANUFACTURER_STRING_2,
+            "product" => LockByte::PRODUCT_STRING_1 | LockByte::PRODUCT_STRING_2,
+            "serial" => LockByte::SERIAL_STRING,
+            "pin-config" => LockByte::PIN_CONFIG,
+            "all" => LockByte::all(),
+            _ => return Err(format!("Unrecognised lock field '{}'", f)),
+        };
+    }
+
+    Ok(fields)
+}
+
+fn parse_pin_level(src: &str) -> Result<(u8, GpioLevel), String> {
+    let (pin, level) = src.split_once('=')
+        .ok_or_else(|| "Expected pin=level, eg. '6=high'".to_string())?;
+
+    let pin = pin.parse::<u8>().map_err(|e| format!("Invalid pin '{}': {}", pin, e))?;
+    let level = level.parse::<GpioLevel>()?;
+
+    Ok((pin, level))
+}
+
+fn parse_hex_u16(src: &str) -> Result<u16, std::num::ParseIntError> {
+    u16::from_str_radix(src, 16)
+}
+
+fn parse_max_power(src: &str) -> Result<u16, String> {
+    let v = src.parse::<u16>().map_err(|e| format!("Invalid current '{}': {}", src, e))?;
+
+    match v <= MAX_POWER_LIMIT_MA {
+        true => Ok(v),
+        false => Err(Cp2130Error::InvalidMaxPower(v).to_string()),
+    }
+}
+
 
 fn main() {
     let opts = Options::from_args();
@@ -147,8 +346,36 @@ fn main() {
             let v = cp2130.version().unwrap();
             info!("Device version: {}", v);
         },
-        Command::SetOutput{pin, mode, state} => {
-            cp2130.set_gpio_mode_level(pin, mode, state).unwrap()
+        Command::Status => {
+            let s = cp2130.snapshot().unwrap();
+
+            info!("Version: {}", s.version);
+            for (i, c) in s.spi.iter().enumerate() {
+                info!("SPI{}: clock: {:?} mode: {:?} cs: {:?} cs pin: {:?} delays: {:?}",
+                    i, c.clock, c.spi_mode, c.cs_mode, c.cs_pin_mode, c.delays);
+            }
+            info!("CS enabled pins: {:?}", s.cs_pins);
+            for (i, p) in s.pins.iter().enumerate() {
+                info!("GPIO{}: function: {:?} push-pull: {} level: {:?}", i, p.function, p.push_pull, p.level);
+            }
+            info!("Clock output: {} Hz (divider: {})", s.clock.frequency, s.clock.divider);
+        },
+        Command::SetOutput{pins, mode} => {
+            let mut levels = GpioLevels::empty();
+            let mut mask = GpioLevels::empty();
+            for (pin, state) in &pins {
+                levels.set_level(*pin, *state).unwrap();
+                mask |= GpioLevels::pin(*pin).unwrap();
+            }
+
+            // Configure pin modes without changing current levels
+            let current = cp2130.get_gpio_values().unwrap();
+            for (pin, _state) in &pins {
+                cp2130.set_gpio_mode_level(*pin, mode, current.level(*pin).unwrap()).unwrap();
+            }
+
+            // Then update all levels at once
+            cp2130.set_gpio_values(levels, mask).unwrap()
         },
         Command::ReadInput{pin, mode} => {
             if let Some(m) = mode {
@@ -157,31 +384,227 @@ fn main() {
             let v = cp2130.get_gpio_level(pin).unwrap();
             info!("Pin: {} value: {}", pin, v);
         },
+        Command::FullThreshold{threshold} => {
+            if let Some(t) = threshold {
+                cp2130.set_full_threshold(t).unwrap();
+            }
+
+            let t = cp2130.full_threshold().unwrap();
+            info!("Full threshold: {}", t);
+        },
+        Command::ClockOut{frequency} => {
+            let mut clk = cp2130.clock_out().unwrap();
+
+            if let Some(target) = frequency {
+                let f = clk.set_frequency(target).unwrap();
+                info!("Set clock output: {} Hz (divider: {}, error: {} Hz)", f.frequency, f.divider, f.error);
+            }
+
+            let f = clk.frequency().unwrap();
+            info!("Clock output: {} Hz (divider: {})", f.frequency, f.divider);
+        },
+        Command::EventCount{mode, window_ms, events_per_rev} => {
+            let mut counter = cp2130.event_counter(mode).unwrap();
+
+            let r = counter.measure(std::time::Duration::from_millis(window_ms)).unwrap();
+            info!("Counted {} events in {:?} ({:.2} Hz)", r.count, r.window, r.frequency());
+
+            if let Some(n) = events_per_rev {
+                info!("Speed: {:.1} RPM", r.rpm(n));
+            }
+        },
+        Command::Watch{pins, interval_ms, debounce, duration_ms, vcd} => {
+            let mask = match pins.is_empty() {
+                true => GpioLevels::all(),
+                false => pins.iter().fold(GpioLevels::empty(), |m, p| m | GpioLevels::from(*p)),
+            };
+
+            let opts = WatchOptions{pins: mask, interval: Duration::from_millis(interval_ms), debounce};
+            let window = opts.latency();
+            let (watcher, rx) = cp2130.watch_channel(opts).unwrap();
+
+            let initial = watcher.initial_levels();
+            for p in GpioPin::all().iter().filter(|p| mask.contains((**p).into())) {
+                info!("GPIO{}: {:?}", p.index(), initial.level(p.index()).unwrap());
+            }
+
+            let mut vcd = vcd.map(|f| {
+                let mut v = VcdWriter::new(std::fs::File::create(f).unwrap(), mask, initial).unwrap();
+                v.set_window(window);
+                v
+            });
+
+            // Print changes until the duration elapses or the watcher stops
+            let deadline = duration_ms.map(|d| Instant::now() + Duration::from_millis(d));
+            loop {
+                let r = match deadline {
+                    Some(d) => rx.recv_timeout(d.saturating_duration_since(Instant::now())).ok(),
+                    None => rx.recv().ok(),
+                };
+                let e = match r {
+                    Some(e) => e,
+                    None => break,
+                };
+
+                info!("{:10.3} ms GPIO{}: {:?}", e.time.as_secs_f64() * 1e3, e.pin.index(), e.edge);
+
+                if let Some(v) = vcd.as_mut() {
+                    v.event(&e).unwrap();
+                }
+            }
+
+            if let Some(v) = vcd.as_mut() {
+                v.flush().unwrap();
+            }
+
+            watcher.stop().unwrap();
+        },
         Command::SpiTransfer{data, spi_opts} => {
             info!("Transmit: {}", hex::encode(&data));
 
-            let mut spi = cp2130.spi(spi_opts.channel, SpiConfig::default()).unwrap();
+            let mut buff = data.clone();
 
-            cp2130.set_gpio_mode_level(spi_opts.cs_pin, GpioMode::PushPull, GpioLevel::Low).unwrap();
+            match spi_opts.cs_pin {
+                Some(cs_pin) => {
+                    let mut spi = cp2130.spi(spi_opts.channel, SpiConfig::default()).unwrap();
 
-            let mut buff = data.clone();
-            
-            spi.transfer_inplace(&mut buff).unwrap();
+                    cp2130.set_gpio_mode_level(cs_pin, GpioMode::PushPull, GpioLevel::Low).unwrap();
 
-            cp2130.set_gpio_mode_level(spi_opts.cs_pin, GpioMode::PushPull, GpioLevel::High).unwrap();
+                    spi.transfer_in_place(&mut buff).unwrap();
+
+                    cp2130.set_gpio_mode_level(cs_pin, GpioMode::PushPull, GpioLevel::High).unwrap();
+                },
+                None => {
+                    let mut spi = cp2130.spi_device(spi_opts.channel, SpiConfig::default()).unwrap();
+                    spi.transaction(&mut [Operation::TransferInPlace(&mut buff)]).unwrap();
+                },
+            }
 
             info!("Received: {}", hex::encode(buff));
         },
         Command::SpiWrite{data, spi_opts} => {
             info!("Transmit: {}", hex::encode(&data));
 
+            match spi_opts.cs_pin {
+                Some(cs_pin) => {
+                    let mut spi = cp2130.spi(spi_opts.channel, SpiConfig::default()).unwrap();
+
+                    cp2130.set_gpio_mode_level(cs_pin, GpioMode::PushPull, GpioLevel::Low).unwrap();
+
+                    spi.write(&data).unwrap();
+
+                    cp2130.set_gpio_mode_level(cs_pin, GpioMode::PushPull, GpioLevel::High).unwrap();
+                },
+                None => {
+                    let mut spi = cp2130.spi_device(spi_opts.channel, SpiConfig::default()).unwrap();
+                    spi.transaction(&mut [Operation::Write(&data)]).unwrap();
+                },
+            }
+        },
+        Command::UsbConfig => {
+            let c = cp2130.usb_config().unwrap();
+            info!("USB config: {:?}", c);
+        },
+        Command::SetUsbConfig{vid, pid, max_power, power_mode, release_version, transfer_priority} => {
+            let mut c = cp2130.usb_config().unwrap();
+            let mut mask = UsbConfigMask::empty();
+
+            if let Some(v) = vid {
+                c.vid = v;
+                mask |= UsbConfigMask::VID;
+            }
+            if let Some(v) = pid {
+                c.pid = v;
+                mask |= UsbConfigMask::PID;
+            }
+            if let Some(v) = max_power {
+                c.set_max_power_ma(v).unwrap();
+                mask |= UsbConfigMask::MAX_POWER;
+            }
+            if let Some(v) = power_mode {
+                c.power_mode = v;
+                mask |= UsbConfigMask::POWER_MODE;
+            }
+            if let Some(v) = release_version {
+                c.release_version = v;
+                mask |= UsbConfigMask::RELEASE_VERSION;
+            }
+            if let Some(v) = transfer_priority {
+                c.transfer_priority = v;
+                mask |= UsbConfigMask::TRANSFER_PRIORITY;
+            }
+
+            cp2130.set_usb_config(&c, mask).unwrap();
+
+            info!("Wrote USB config: {:?} (mask: {:?})", c, mask);
+        },
+        Command::GetString{field} => {
+            let v = cp2130.usb_string(field).unwrap();
+            info!("{:?} string: '{}'", field, v);
+        },
+        Command::SetString{field, value} => {
+            cp2130.set_usb_string(field, &value).unwrap();
+            info!("Wrote {:?} string: '{}'", field, value);
+        },
+        Command::SpiReadRtr{len, timeout_ms, spi_opts} => {
             let mut spi = cp2130.spi(spi_opts.channel, SpiConfig::default()).unwrap();
 
-            cp2130.set_gpio_mode_level(spi_opts.cs_pin, GpioMode::PushPull, GpioLevel::Low).unwrap();
+            let mut buff = vec![0u8; len];
+            let n = spi.read_rtr(&mut buff, std::time::Duration::from_millis(timeout_ms)).unwrap();
+
+            info!("Received: {}", hex::encode(&buff[..n]));
+        },
+        Command::PinConfig => {
+            let c = cp2130.pin_config().unwrap();
+            for (i, f) in c.functions().iter().enumerate() {
+                info!("GPIO{}: {:?}", i, f);
+            }
+            info!("Reset level: {:?} mode: {:?}", c.reset_level, c.reset_mode());
+            info!("Suspend level: {:?} mode: {:?}", c.suspend_level, c.suspend_mode);
+            info!("Wakeup mask: {:?} match: {:?}", c.wakeup_mask, c.wakeup_match);
+            info!("Clock divider: {}", c.clock_divider);
+        },
+        Command::SetPinFunction{pin, function} => {
+            let mut c = cp2130.pin_config().unwrap();
+            c.set_function(pin, function).unwrap();
+            cp2130.set_pin_config(&c).unwrap();
+
+            info!("Set GPIO{} function: {:?}", pin, function);
+        },
+        Command::LockStatus => {
+            let l = cp2130.lock_byte().unwrap();
+            info!("Unlocked fields: {:?}", l);
+            info!("Locked fields: {:?}", LockByte::all() - l);
+        },
+        Command::Lock{fields, confirm} => {
+            if !confirm {
+                error!("Locking {:?} is irreversible, re-run with --confirm to continue", fields);
+                return;
+            }
 
-            spi.write(&data).unwrap();
+            cp2130.lock(LockConfirmation::irreversibly_lock(fields)).unwrap();
+            info!("Locked fields: {:?}", fields);
+        },
+        Command::PromRead{file} => {
+            let prom = cp2130.prom_config().unwrap();
+            prom.save(&file).unwrap();
 
-            cp2130.set_gpio_mode_level(spi_opts.cs_pin, GpioMode::PushPull, GpioLevel::High).unwrap();
+            info!("Saved PROM image to: {}", file.display());
+        },
+        Command::PromWrite{file, backup} => {
+            let prom = PromConfig::load(&file).unwrap();
+
+            let backup = match backup {
+                Some(b) => b,
+                None => {
+                    let serial: String = cp2130.usb_string(UsbString::Serial).unwrap()
+                        .chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+                    PathBuf::from(format!("cp2130-prom-backup-{}.bin", serial))
+                },
+            };
+            cp2130.set_prom_config(&prom, &backup).unwrap();
+
+            info!("Wrote PROM image from: {}", file.display());
         },
         Command::Test(opts) => {
             run_tests(&mut cp2130, &opts);
@@ -198,13 +621,13 @@ fn run_tests(cp2130: &mut Cp2130, opts: &TestOpts) {
 
     cp2130.set_gpio_mode_level(opts.write_pin, GpioMode::PushPull, GpioLevel::Low).unwrap();
     let v = cp2130.get_gpio_level(opts.read_pin).unwrap();
-    if v != false {
+    if v {
         error!("GPIO read error");
     }
 
     cp2130.set_gpio_mode_level(opts.write_pin, GpioMode::PushPull, GpioLevel::High).unwrap();
     let v = cp2130.get_gpio_level(opts.read_pin).unwrap();
-    if v != true {
+    if !v {
         error!("GPIO read error");
     }
 
@@ -239,7 +662,7 @@ fn run_tests(cp2130: &mut Cp2130, opts: &TestOpts) {
 
     cp2130.spi_write_read(&data, &mut buff).unwrap();
 
-    if &data != &buff {
+    if data != buff {
         error!("SPI transfer (short) error ({:?} vs. {:?})", data, buff);
     }
 
@@ -254,10 +677,25 @@ fn run_tests(cp2130: &mut Cp2130, opts: &TestOpts) {
 
     cp2130.spi_write_read(&data, &mut buff).unwrap();
 
-    if &data != &buff {
+    if data != buff {
         error!("SPI transfer (long) error ({:?} vs. {:?})", data, buff);
     }
 
     info!("SPI transfer (long) okay");
 
+
+    info!("Testing SPI transfer ({} bytes)", opts.transfer_len);
+
+    let mut rng = rand::thread_rng();
+    let data: Vec<u8> = (0..opts.transfer_len).map(|_| rng.gen() ).collect();
+    let mut buff = vec![0u8; data.len()];
+
+    cp2130.spi_write_read(&data, &mut buff).unwrap();
+
+    if data != buff {
+        error!("SPI transfer ({} bytes) error", opts.transfer_len);
+    }
+
+    info!("SPI transfer ({} bytes) okay", opts.transfer_len);
+
 }
@@ -3,19 +3,25 @@
 //! 
 //! Copyright 2019 Ryan Kurte
 
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant};
 use std::str::FromStr;
 
 use byteorder::{LE, BE, ByteOrder};
 use bitflags::bitflags;
 
-use rusb::{Device as UsbDevice, Context as UsbContext, DeviceDescriptor, DeviceHandle, Direction, TransferType};
+use rusb::{Device as UsbDevice, Context as UsbContext, DeviceDescriptor, Direction, TransferType};
 
-use embedded_hal::spi::{Mode as SpiMode, Phase, Polarity, MODE_0};
+use embedded_hal::spi::{Mode as SpiMode, Operation, Phase, Polarity, MODE_0};
 
-use crate::Error;
+use crate::{Error, ErrorKind, Op};
+use crate::otp::{PinConfig, PinFunction, GPIO_COUNT};
+use crate::pacing::{Pacer, SystemClock};
+use crate::transfer::{Source, Sink, DEFAULT_TRANSFER_DEPTH, DEFAULT_TRANSFER_SIZE};
+#[cfg(feature = "async")]
+use crate::transfer::Complete;
+use crate::transport::{Transport, UsbTransport};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct Info {
     manufacturer: String,
     product: String,
@@ -32,10 +38,19 @@ pub enum Commands {
     GetGpioChipSelect = 0x24,
     GetGpioModeAndLevel = 0x22,
     GetGpioValues = 0x20,
+    GetLockByte = 0x6E,
+    GetManufacturingString1 = 0x62,
+    GetManufacturingString2 = 0x64,
+    GetPinConfig = 0x6C,
+    GetProductString1 = 0x66,
+    GetProductString2 = 0x68,
+    GetPromConfig = 0x70,
     GetRtrState = 0x36,
     GetSpiWord = 0x30,
     GetSpiDelay = 0x32,
     GetReadOnlyVersion = 0x11,
+    GetSerialString = 0x6A,
+    GetUsbConfig = 0x60,
     ResetDevice = 0x10,
     SetClockDivider = 0x47,
     SetEventCOunter = 0x45,
@@ -43,9 +58,18 @@ pub enum Commands {
     SetGpioChipSelect = 0x25,
     SetGpioModeAndLevel = 0x23,
     SetGpioValues = 0x21,
+    SetLockByte = 0x6F,
+    SetManufacturingString1 = 0x63,
+    SetManufacturingString2 = 0x65,
+    SetPinConfig = 0x6D,
+    SetProductString1 = 0x67,
+    SetProductString2 = 0x69,
+    SetPromConfig = 0x71,
     SetRtrStop = 0x37,
     SetSpiWord = 0x31,
+    SetSerialString = 0x6B,
     SetSpiDelay = 0x33,
+    SetUsbConfig = 0x61,
 }
 
 /// Default CP2130 VID
@@ -54,6 +78,15 @@ pub const VID: u16 = 0x10c4;
 /// Default CP2130 PID
 pub const PID: u16 = 0x87a0;
 
+/// Key required in wValue for commands that write to the OTP ROM
+pub const OTP_MEMORY_KEY: u16 = 0xA5F1;
+
+/// Bulk endpoint packet size
+pub(crate) const PACKET_SIZE: usize = 64;
+
+/// Timeout for reads discarding stale bulk IN data
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(10);
+
 bitflags!(
     /// USB request type flags
     pub struct RequestType: u8 {
@@ -91,6 +124,119 @@ bitflags!(
     }
 );
 
+/// GPIO pin flags, indexed by pin number
+pub(crate) const GPIO_PINS: [GpioLevels; 11] = [
+    GpioLevels::GPIO_0, GpioLevels::GPIO_1, GpioLevels::GPIO_2, GpioLevels::GPIO_3,
+    GpioLevels::GPIO_4, GpioLevels::GPIO_5, GpioLevels::GPIO_6, GpioLevels::GPIO_7,
+    GpioLevels::GPIO_8, GpioLevels::GPIO_9, GpioLevels::GPIO_10,
+];
+
+/// GPIO pin identifiers
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum GpioPin {
+    Gpio0 = 0,
+    Gpio1 = 1,
+    Gpio2 = 2,
+    Gpio3 = 3,
+    Gpio4 = 4,
+    Gpio5 = 5,
+    Gpio6 = 6,
+    Gpio7 = 7,
+    Gpio8 = 8,
+    Gpio9 = 9,
+    Gpio10 = 10,
+}
+
+/// All GPIO pins, indexed by pin number
+const GPIO_PIN_IDS: [GpioPin; 11] = [
+    GpioPin::Gpio0, GpioPin::Gpio1, GpioPin::Gpio2, GpioPin::Gpio3,
+    GpioPin::Gpio4, GpioPin::Gpio5, GpioPin::Gpio6, GpioPin::Gpio7,
+    GpioPin::Gpio8, GpioPin::Gpio9, GpioPin::Gpio10,
+];
+
+impl GpioPin {
+    /// Fetch all GPIO pins
+    pub fn all() -> &'static [GpioPin] {
+        &GPIO_PIN_IDS
+    }
+
+    /// Fetch the pin number
+    pub fn index(&self) -> u8 {
+        *self as u8
+    }
+}
+
+impl std::convert::TryFrom<u8> for GpioPin {
+    type Error = Error;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        GPIO_PIN_IDS.get(v as usize).copied().ok_or(Error::InvalidPin(v))
+    }
+}
+
+impl From<GpioPin> for GpioLevels {
+    fn from(p: GpioPin) -> Self {
+        GPIO_PINS[p as usize]
+    }
+}
+
+impl FromStr for GpioPin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s.trim_start_matches("gpio").trim_start_matches("GPIO")
+            .parse::<u8>().map_err(|e| format!("Invalid pin '{}': {}", s, e))?;
+
+        GPIO_PIN_IDS.get(v as usize).copied().ok_or_else(|| Error::InvalidPin(v).to_string())
+    }
+}
+
+impl GpioLevels {
+    /// Fetch the flag for a given GPIO pin index
+    pub fn pin(index: u8) -> Result<Self, Error> {
+        GPIO_PINS.get(index as usize).copied().ok_or(Error::InvalidPin(index))
+    }
+
+    /// Build a set of flags from GPIO pin indices
+    pub fn from_pins(pins: &[u8]) -> Result<Self, Error> {
+        pins.iter().try_fold(Self::empty(), |a, p| Ok(a | Self::pin(*p)?))
+    }
+
+    /// Fetch the GPIO pin indices for the set flags
+    pub fn pins(&self) -> Vec<u8> {
+        GPIO_PINS.iter().enumerate()
+            .filter(|(_i, p)| self.contains(**p))
+            .map(|(i, _p)| i as u8)
+            .collect()
+    }
+
+    /// Fetch the level of a given GPIO pin
+    pub fn level(&self, index: u8) -> Result<GpioLevel, Error> {
+        match self.contains(Self::pin(index)?) {
+            true => Ok(GpioLevel::High),
+            false => Ok(GpioLevel::Low),
+        }
+    }
+
+    /// Set the level of a given GPIO pin
+    pub fn set_level(&mut self, index: u8, level: GpioLevel) -> Result<(), Error> {
+        self.set(Self::pin(index)?, level == GpioLevel::High);
+        Ok(())
+    }
+
+    /// Decode GPIO flags from the (big endian) device representation
+    pub(crate) fn decode(buff: &[u8]) -> Self {
+        Self::from_bits_truncate(BE::read_u16(buff))
+    }
+
+    /// Encode GPIO flags to the (big endian) device representation
+    pub(crate) fn encode(&self) -> [u8; 2] {
+        let mut buff = [0u8; 2];
+        BE::write_u16(&mut buff, self.bits());
+        buff
+    }
+}
+
 /// GPIO mode enumeration
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum GpioMode {
@@ -107,7 +253,7 @@ impl FromStr for GpioMode {
             "input" => Ok(Self::Input),
             "open-drain" => Ok(Self::OpenDrain),
             "push-pull" => Ok(Self::PushPull),
-            _ => Err(format!("Unrecognised GPIO mode, try 'input', 'open-drain', or 'push-pull'")),
+            _ => Err("Unrecognised GPIO mode, try 'input', 'open-drain', or 'push-pull'".to_string()),
         }
     }
 }
@@ -126,13 +272,167 @@ impl FromStr for GpioLevel {
         match s {
             "1" | "true" | "high" => Ok(Self::High),
             "0" | "false" | "low" => Ok(Self::Low),
-            _ => Err(format!("Unrecognised GPIO level, try 'high' or 'low'")),
+            _ => Err("Unrecognised GPIO level, try 'high' or 'low'".to_string()),
         }
     }
 }
 
-/// Transfer command enumeration
+/// Base frequency for the CLKOUT clock generator
+pub const CLOCK_OUT_BASE_HZ: u32 = 24_000_000;
+
+/// GPIO pin used for CLKOUT
+pub const CLOCK_OUT_PIN: u8 = 5;
+
+/// Clock output frequency, derived from the 24 MHz base clock and a divider
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ClockFrequency {
+    /// Clock divider (1 to 256)
+    pub divider: u16,
+    /// Actual output frequency in Hz
+    pub frequency: u32,
+    /// Difference between the actual and target frequency in Hz
+    pub error: i32,
+}
+
+impl ClockFrequency {
+    /// Compute the output frequency for a given divider (1 to 256)
+    pub fn from_divider(divider: u16) -> Result<Self, Error> {
+        if divider == 0 || divider > 256 {
+            return Err(Error::InvalidDivider(divider))
+        }
+
+        Ok(Self{ divider, frequency: CLOCK_OUT_BASE_HZ / divider as u32, error: 0 })
+    }
+
+    /// Select the divider producing the closest frequency to the target
+    pub fn for_target(target: u32) -> Result<Self, Error> {
+        let min = CLOCK_OUT_BASE_HZ / 256;
+        if target < min || target > CLOCK_OUT_BASE_HZ {
+            return Err(Error::InvalidFrequency(target))
+        }
+
+        let divider = ((CLOCK_OUT_BASE_HZ + target / 2) / target).clamp(1, 256) as u16;
+
+        let mut f = Self::from_divider(divider)?;
+        f.error = f.frequency as i32 - target as i32;
+
+        Ok(f)
+    }
+
+    /// Encode the divider for a Set Clock Divider request, 0 represents a divider of 256
+    pub(crate) fn encode(&self) -> u8 {
+        (self.divider & 0xFF) as u8
+    }
+
+    /// Decode the divider from a Get Clock Divider response
+    pub(crate) fn decode(v: u8) -> Self {
+        let divider = match v {
+            0 => 256,
+            d => d as u16,
+        };
+
+        Self{ divider, frequency: CLOCK_OUT_BASE_HZ / divider as u32, error: 0 }
+    }
+}
+
+/// Event counter mode enumeration
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum EventCounterMode {
+    RisingEdge = 0x04,
+    FallingEdge = 0x05,
+    NegativePulse = 0x06,
+    PositivePulse = 0x07,
+}
+
+impl EventCounterMode {
+    /// Decode the event counter mode from the lower bits of a mode byte
+    pub(crate) fn decode(v: u8) -> Option<Self> {
+        match v & 0x07 {
+            0x04 => Some(EventCounterMode::RisingEdge),
+            0x05 => Some(EventCounterMode::FallingEdge),
+            0x06 => Some(EventCounterMode::NegativePulse),
+            0x07 => Some(EventCounterMode::PositivePulse),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for EventCounterMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rising" => Ok(Self::RisingEdge),
+            "falling" => Ok(Self::FallingEdge),
+            "negative-pulse" => Ok(Self::NegativePulse),
+            "positive-pulse" => Ok(Self::PositivePulse),
+            _ => Err("Unrecognised event counter mode, try 'rising', 'falling', 'negative-pulse', or 'positive-pulse'".to_string()),
+        }
+    }
+}
+
+/// GPIO pin used for the event counter
+pub const EVENT_COUNTER_PIN: u8 = 4;
+
+/// Event counter overflow flag in the mode byte
+const EVENT_COUNTER_OVERFLOW: u8 = 1 << 7;
+
+/// Event counter value
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct EventCount {
+    /// Event counter mode, None if GPIO4 is not in an event counter mode
+    pub mode: Option<EventCounterMode>,
+    /// Number of events counted
+    pub count: u16,
+    /// Indicates the counter has overflowed
+    pub overflow: bool,
+}
+
+/// Number of SPI channels
+pub const SPI_CHANNEL_COUNT: usize = 11;
+
+/// Runtime state of a single GPIO pin
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct PinState {
+    /// Pin function from the pin configuration
+    pub function: PinFunction,
+    /// Set if the pin is driven as a push-pull output, otherwise the pin is an input or open-drain output
+    pub push_pull: bool,
+    /// Current pin level
+    pub level: GpioLevel,
+}
+
+/// Snapshot of the full device state
 #[derive(Debug, PartialEq, Clone)]
+pub struct DeviceState {
+    /// Chip version
+    pub version: u16,
+    /// Per-channel SPI configuration
+    pub spi: Vec<SpiConfig>,
+    /// Channels with chip select enabled (bit n for channel n)
+    pub cs_channels: u16,
+    /// Pins with chip select enabled
+    pub cs_pins: GpioLevels,
+    /// Per-pin mode and level
+    pub pins: Vec<PinState>,
+    /// CLKOUT clock divider and frequency
+    pub clock: ClockFrequency,
+}
+
+/// GPIO pin used for the ready-to-read (RTR) input
+pub const RTR_PIN: u8 = 3;
+
+/// Ready-to-read (RTR) state
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RtrState {
+    /// RTR reads are active
+    Active = 0x00,
+    /// RTR reads are stopped
+    Stopped = 0x01,
+}
+
+/// Transfer command enumeration
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum TransferCommand {
     Read        = 0x00,
     Write       = 0x01,
@@ -143,21 +443,31 @@ pub enum TransferCommand {
 /// Inner struct contains CP2130 IO functions
 /// This is used to split SPI and GPIO components
 pub(crate) struct Inner {
-    _device: UsbDevice<UsbContext>,
-    handle: DeviceHandle<UsbContext>,
-    endpoints: Endpoints,
+    transport: Box<dyn Transport>,
 
-    pub(crate) gpio_allocated: [bool; 11],
+    pub(crate) gpio_allocated: [bool; GPIO_COUNT],
+    /// Output levels last written to the GPIO pins, None where unknown (ie. following a reset)
+    gpio_levels: Option<GpioLevels>,
     spi_clock: SpiClock,
+    /// Pin configuration, None if it could not be read
+    pub(crate) pin_config: Option<PinConfig>,
+    spi_active: Option<(u8, SpiConfig)>,
+    /// Channels with chip select enabled (bit n for channel n)
+    cs_enabled: u16,
+    pub(crate) pacer: Pacer,
+    policy: TransferPolicy,
+    /// Event thread driving async operations, started on first use
+    #[cfg(feature = "async")]
+    pub(crate) events: Option<crate::asynch::Events>,
 }
 
-/// Device specific endpoints
-/// TODO: given it's one device this could all be hard-coded
-#[derive(Debug)]
-struct Endpoints {
-    control: Endpoint,
-    read: Endpoint,
-    write: Endpoint,
+/// SPI command started on the transfer engine, see `Inner::spi_start`
+#[cfg(feature = "async")]
+pub(crate) struct PendingSpi {
+    command: TransferCommand,
+    len: usize,
+    read_len: usize,
+    time: Duration,
 }
 
 /// Internal endpoint representations
@@ -180,6 +490,78 @@ pub struct UsbOptions {
     #[cfg_attr(feature = "structopt", structopt(long))]
     /// Attempt to claim interface
     pub claim_interface: bool,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "4"))]
+    /// Number of bulk transfers to keep in flight per direction
+    pub transfer_depth: usize,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "4096"))]
+    /// Size of each bulk transfer buffer in bytes
+    pub transfer_size: usize,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "100"))]
+    /// Safety margin added to the expected SPI transfer time (in us)
+    pub pacing_margin_us: u64,
+
+    #[cfg_attr(feature = "structopt", structopt(flatten))]
+    /// Timeout and retry policy for USB transfers
+    pub policy: TransferPolicy,
+}
+
+/// Timeout and retry policy for USB transfers
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+pub struct TransferPolicy {
+    #[cfg_attr(feature = "structopt", structopt(long = "control-timeout-ms", default_value = "200", parse(try_from_str = parse_millis)))]
+    /// Timeout for control requests (in ms)
+    pub control_timeout: Duration,
+
+    #[cfg_attr(feature = "structopt", structopt(long = "bulk-timeout-ms", default_value = "200", parse(try_from_str = parse_millis)))]
+    /// Base timeout for bulk transfers, extended by the expected SPI transfer time (in ms)
+    pub bulk_timeout: Duration,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "2"))]
+    /// Number of retries for failed USB requests
+    pub retries: u32,
+
+    #[cfg_attr(feature = "structopt", structopt(long = "backoff-ms", default_value = "10", parse(try_from_str = parse_millis)))]
+    /// Delay before the first retry, doubled for each subsequent retry (in ms)
+    pub backoff: Duration,
+}
+
+impl Default for TransferPolicy {
+    fn default() -> Self {
+        Self {
+            control_timeout: Duration::from_millis(200),
+            bulk_timeout: Duration::from_millis(200),
+            retries: 2,
+            backoff: Duration::from_millis(10),
+        }
+    }
+}
+
+impl TransferPolicy {
+    /// Delay before a given retry attempt
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.backoff.checked_mul(2u32.saturating_pow(attempt)).unwrap_or(Duration::MAX)
+    }
+
+    /// Check whether a failed request may succeed on retry
+    pub fn recoverable(e: &Error) -> bool {
+        match e.kind() {
+            ErrorKind::Timeout | ErrorKind::Stall | ErrorKind::ShortTransfer => true,
+            ErrorKind::Other => matches!(e,
+                Error::Usb{source: rusb::Error::Io, ..} | Error::Usb{source: rusb::Error::Interrupted, ..} |
+                Error::Usb{source: rusb::Error::Overflow, ..}
+            ),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(feature = "structopt")]
+fn parse_millis(s: &str) -> Result<Duration, std::num::ParseIntError> {
+    s.parse::<u64>().map(Duration::from_millis)
 }
 
 impl Default for UsbOptions {
@@ -199,6 +581,13 @@ impl Default for UsbOptions {
             claim_interface: true,
             #[cfg(target_os = "macos")]
             claim_interface: true,
+
+            transfer_depth: DEFAULT_TRANSFER_DEPTH,
+            transfer_size: DEFAULT_TRANSFER_SIZE,
+
+            pacing_margin_us: SPI_OP_DELAY_US,
+
+            policy: TransferPolicy::default(),
         }
     }
 }
@@ -206,14 +595,14 @@ impl Default for UsbOptions {
 impl Inner {
     /// Create a new CP2130 instance from a libusb device and descriptor
     pub fn new(device: UsbDevice<UsbContext>, descriptor: DeviceDescriptor, opts: UsbOptions) -> Result<(Self, Info), Error> {
-        let timeout = Duration::from_millis(200);
+        let timeout = opts.policy.control_timeout;
         
         // Fetch device handle
-        let mut handle = match device.open() {
+        let handle = match device.open() {
             Ok(v) => v,
             Err(e) => {
                 error!("Opening device: {}", e);
-                return Err(Error::Usb(e))
+                return Err(Error::usb(Op::Device, e))
             }
         };
 
@@ -228,7 +617,7 @@ impl Inner {
         trace!("Languages: {:?}", languages);
 
         // Check a language is available
-        if languages.len() == 0 {
+        if languages.is_empty() {
             return Err(Error::NoLanguages)
         }
 
@@ -327,9 +716,26 @@ impl Inner {
         };
         handle.set_active_configuration(read.config)?;
         
-        // Build endpoints
-        let endpoints = Endpoints{control, write, read};
-        Ok((Inner{_device: device, handle, endpoints, gpio_allocated: [false; 11], spi_clock: SpiClock::Clock12Mhz}, info))
+        // Build transport
+        let transport = UsbTransport::new(device, handle, write.address, read.address, opts.transfer_depth, opts.transfer_size)?;
+
+        let inner = Inner::with_transport(Box::new(transport), &opts);
+
+        Ok((inner, info))
+    }
+
+    /// Create a CP2130 instance using the provided transport
+    pub(crate) fn with_transport(transport: Box<dyn Transport>, opts: &UsbOptions) -> Self {
+        let mut inner = Inner{transport, gpio_allocated: [false; GPIO_COUNT], gpio_levels: None, spi_clock: SpiClock::Clock12Mhz, pin_config: None, spi_active: None, cs_enabled: 0, pacer: Pacer::new(SystemClock, Duration::from_micros(opts.pacing_margin_us)), policy: opts.policy.clone(),
+            #[cfg(feature = "async")]
+            events: None,
+        };
+
+        // Fetch pin configuration so GPIO allocations can be checked against pin functions
+        inner.load_pin_config();
+        inner.load_gpio_levels();
+
+        inner
     }
 }
 
@@ -342,9 +748,11 @@ pub enum SpiClock {
     Clock1_5MHz,
     Clock750KHz,
     Clock375MHz,
+    Clock187_5KHz,
+    Clock93_75KHz,
 }
 
-/// SPI operation delay added to transaction time to ensure we don't clobber previous SPI transactions
+/// Default SPI operation margin added to transaction time to ensure we don't clobber previous SPI transactions
 pub const SPI_OP_DELAY_US: u64 = 100;
 
 impl SpiClock {
@@ -356,12 +764,28 @@ impl SpiClock {
             SpiClock::Clock1_5MHz => 1_500_000,
             SpiClock::Clock750KHz => 750_000,
             SpiClock::Clock375MHz => 375_000,
+            SpiClock::Clock187_5KHz => 187_500,
+            SpiClock::Clock93_75KHz => 93_750,
+        }
+    }
+
+    /// Decode the SPI clock from the lower bits of an SPI control word
+    pub(crate) fn decode(v: u8) -> Self {
+        match v & 0b0111 {
+            0 => SpiClock::Clock12Mhz,
+            1 => SpiClock::Clock6MHz,
+            2 => SpiClock::Clock3MHz,
+            3 => SpiClock::Clock1_5MHz,
+            4 => SpiClock::Clock750KHz,
+            5 => SpiClock::Clock375MHz,
+            6 => SpiClock::Clock187_5KHz,
+            _ => SpiClock::Clock93_75KHz,
         }
     }
 
     pub fn transfer_time(&self, len_bytes: u64) -> std::time::Duration {
-        let micros = len_bytes * 8 * 1_000_000 / self.freq();
-        Duration::from_micros(micros + SPI_OP_DELAY_US)
+        let micros = len_bytes.saturating_mul(8 * 1_000_000) / self.freq();
+        Duration::from_micros(micros)
     }
 }
 
@@ -376,6 +800,8 @@ impl std::convert::TryFrom<usize> for SpiClock {
             1_500_000 => Ok(SpiClock::Clock1_5MHz),
             750_000 => Ok(SpiClock::Clock750KHz),
             375_000 => Ok(SpiClock::Clock375MHz),
+            187_500 => Ok(SpiClock::Clock187_5KHz),
+            93_750 => Ok(SpiClock::Clock93_75KHz),
             _ => Err(Error::InvalidBaud),
         }
     }
@@ -405,15 +831,49 @@ bitflags!(
     }
 );
 
+/// SPI delay configuration, delays are in units of 10 us
 #[derive(Debug, PartialEq, Clone)]
 pub struct SpiDelays {
-    mask: DelayMask,
-    pre_deassert: u8,
-    post_assert: u8,
-    inter_byte: u8,
+    /// Enabled delays
+    pub mask: DelayMask,
+    /// Delay between the last byte and chip select de-assertion
+    pub pre_deassert: u16,
+    /// Delay between chip select assertion and the first byte
+    pub post_assert: u16,
+    /// Delay between bytes
+    pub inter_byte: u16,
+}
+
+impl Default for SpiDelays {
+    fn default() -> Self {
+        Self {
+            mask: DelayMask::empty(),
+            pre_deassert: 0,
+            post_assert: 0,
+            inter_byte: 0,
+        }
+    }
+}
+
+impl SpiDelays {
+    /// Delay between chip select assertion and the first byte, if enabled
+    pub fn post_assert_delay(&self) -> Duration {
+        match self.mask.contains(DelayMask::POST_ASSERT) {
+            true => Duration::from_micros(self.post_assert as u64 * 10),
+            false => Duration::from_secs(0),
+        }
+    }
+
+    /// Delay between the last byte and chip select de-assertion, if enabled
+    pub fn pre_deassert_delay(&self) -> Duration {
+        match self.mask.contains(DelayMask::PRE_DEASSERT) {
+            true => Duration::from_micros(self.pre_deassert as u64 * 10),
+            false => Duration::from_secs(0),
+        }
+    }
 }
 
-#[derive(PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct SpiConfig {
     pub clock: SpiClock, 
     pub spi_mode: SpiMode, 
@@ -429,12 +889,7 @@ impl Default for SpiConfig {
             spi_mode: MODE_0,
             cs_mode: CsMode::Disabled,
             cs_pin_mode: GpioMode::PushPull,
-            delays: SpiDelays {
-                mask: DelayMask::empty(),
-                pre_deassert: 0,
-                post_assert: 0,
-                inter_byte: 0,
-            }
+            delays: SpiDelays::default(),
         }
     }
 }
@@ -446,14 +901,46 @@ impl Inner{
     pub(crate) fn spi_configure(&mut self, channel: u8, config: SpiConfig) -> Result<(), Error> {
         debug!("Setting SPI channel: {:?} clock: {:?} cs mode: {:?}", channel, config.clock, config.cs_mode);
 
+        if channel as usize >= SPI_CHANNEL_COUNT {
+            return Err(Error::InvalidChannel(channel))
+        }
+
+        // Chip select for other channels is left as configured, each channel's
+        // chip select is only asserted during transfers on that channel
+        self.spi_active = None;
+
         // Set SPI channel configuration
         self.set_spi_word(channel, config.clock, config.spi_mode, config.cs_pin_mode)?;
 
         // Configure chip select
-        self.set_gpio_chip_select(channel, config.cs_mode)?;
+        self.set_gpio_chip_select(channel, config.cs_mode.clone())?;
 
         // Configure delays
-        self.set_spi_delay(channel, config.delays)?;
+        self.set_spi_delay(channel, config.delays.clone())?;
+
+        self.spi_active = Some((channel, config));
+
+        Ok(())
+    }
+
+    /// Select an SPI channel and configuration for following transfers,
+    /// the device is only reconfigured if the channel or configuration has changed
+    pub(crate) fn spi_select(&mut self, channel: u8, config: &SpiConfig) -> Result<(), Error> {
+        match &self.spi_active {
+            Some((c, p)) if *c == channel && p == config => Ok(()),
+            _ => self.spi_configure(channel, config.clone()),
+        }
+    }
+
+    /// Disable chip select for a channel if it is enabled, so the pin may be used as GPIO
+    pub(crate) fn spi_release(&mut self, channel: u8) -> Result<(), Error> {
+        if self.cs_enabled & (1 << channel) != 0 {
+            self.set_gpio_chip_select(channel, CsMode::Disabled)?;
+        }
+
+        if matches!(&self.spi_active, Some((c, _p)) if *c == channel) {
+            self.spi_active = None;
+        }
 
         Ok(())
     }
@@ -483,13 +970,8 @@ impl Inner{
             flags
         ];
 
-        self.handle.write_control(
-            (RequestType::HOST_TO_DEVICE | RequestType::TYPE_VENDOR).bits(), 
-            Commands::SetSpiWord as u8,
-            0, 0,
-            &cmd,
-            Duration::from_millis(200)
-        )?;
+        self.control_write(Commands::SetSpiWord, 0, 0, &cmd)
+            .map_err(|e| e.with_op(Op::Channel(Commands::SetSpiWord, channel)))?;
 
         self.spi_clock = clock;
 
@@ -498,267 +980,1279 @@ impl Inner{
 
     pub(crate) fn reset(&mut self) -> Result<(), Error> {
 
-        self.handle.write_control(
-            (RequestType::HOST_TO_DEVICE | RequestType::TYPE_VENDOR).bits(), 
-            Commands::ResetDevice as u8,
-            0, 0,
-            &[],
-            Duration::from_millis(200)
-        )?;
+        self.control_write(Commands::ResetDevice, 0, 0, &[])?;
+
+        // SPI and GPIO state is lost on reset
+        self.spi_active = None;
+        self.cs_enabled = 0;
+        self.pacer.idle();
+        self.gpio_levels = None;
+
+        // Pin configuration changes take effect on reset
+        self.load_pin_config();
+        self.load_gpio_levels();
 
         Ok(())
     }
 
     pub(crate) fn set_spi_delay(&mut self, channel: u8, delays: SpiDelays) -> Result<(), Error> {
 
-        let cmd = [
-            channel,
-            delays.mask.bits(),
-            delays.inter_byte,
-            delays.post_assert,
-            delays.pre_deassert,
-        ];
+        let mut cmd = [0u8; 8];
+        cmd[0] = channel;
+        cmd[1] = delays.mask.bits();
+        BE::write_u16(&mut cmd[2..], delays.inter_byte);
+        BE::write_u16(&mut cmd[4..], delays.post_assert);
+        BE::write_u16(&mut cmd[6..], delays.pre_deassert);
 
-        self.handle.write_control(
-            (RequestType::HOST_TO_DEVICE | RequestType::TYPE_VENDOR).bits(), 
-            Commands::SetSpiDelay as u8,
-            0, 0,
-            &cmd,
-            Duration::from_millis(200)
-        )?;
+        self.control_write(Commands::SetSpiDelay, 0, 0, &cmd)
+            .map_err(|e| e.with_op(Op::Channel(Commands::SetSpiDelay, channel)))?;
 
         Ok(())
     }
 
     pub(crate) fn set_gpio_chip_select(&mut self, channel: u8, cs_mode: CsMode) -> Result<(), Error> {
+        let enabled = match cs_mode {
+            CsMode::Disabled => self.cs_enabled & !(1 << channel),
+            CsMode::Enabled => self.cs_enabled | (1 << channel),
+            CsMode::Exclusive => 1 << channel,
+        };
 
         let cmd = [
             channel,
             cs_mode as u8,
         ];
 
-        self.handle.write_control(
-            (RequestType::HOST_TO_DEVICE | RequestType::TYPE_VENDOR).bits(), 
-            Commands::SetGpioChipSelect as u8,
-            0, 0,
-            &cmd,
-            Duration::from_millis(200)
-        )?;
+        self.control_write(Commands::SetGpioChipSelect, 0, 0, &cmd)
+            .map_err(|e| e.with_op(Op::Channel(Commands::SetGpioChipSelect, channel)))?;
+
+        self.cs_enabled = enabled;
 
         Ok(())
     }
 
     /// Read from the SPI device
     pub(crate) fn spi_read(&mut self, buff: &mut [u8]) -> Result<usize, Error> {
-        let mut cmd = [0u8; 8];
-        cmd[2] = TransferCommand::Read as u8;
-        LE::write_u32(&mut cmd[4..], buff.len() as u32);
-
+        let len = buff.len();
+        let cmd = Self::transfer_header(TransferCommand::Read, len)?;
 
         trace!("SPI read (cmd: {:?})", cmd);
 
-        self.handle.write_bulk(
-            self.endpoints.write.address,
-            &cmd,
-            Duration::from_millis(200),
-        )?;
-
-        // TODO: loop for > 64-byte packets
-        let mut index = 0;
-
-        while index < buff.len() {
-            let remainder = if buff.len() > index + 64 {
-                64
-            } else {
-                buff.len() - index
-            };
-
-            debug!("SPI read (i: {}, rem: {})", index, remainder);
+        self.pacer.wait();
 
-            let n = self.handle.read_bulk(
-                self.endpoints.read.address,
-                &mut buff[index..index+remainder],
-                Duration::from_millis(200),
-            )?;
-
-            index += n;
-        }
+        let n = self.bulk(TransferCommand::Read, Source{header: &cmd, data: &[], len: cmd.len()}, Some(buff), len)?;
 
         trace!("SPI read done");
 
-        Ok(index)
+        Ok(n)
     }
 
     /// Write to the SPI device
     pub(crate) fn spi_write(&mut self, buff: &[u8]) -> Result<(), Error> {
-
-        let mut cmd = vec![0u8; buff.len() + 8];
-
-        cmd[2] = TransferCommand::Write as u8;
-        LE::write_u32(&mut cmd[4..], buff.len() as u32);
-        (&mut cmd[8..]).copy_from_slice(buff);
+        let cmd = Self::transfer_header(TransferCommand::Write, buff.len())?;
 
         let t = self.spi_clock.transfer_time(buff.len() as u64);
         trace!("SPI write (cmd: {:?} time: {} us)", cmd, t.as_micros());
 
-        self.handle.write_bulk(
-            self.endpoints.write.address,
-            &cmd,
-            Duration::from_millis(200),
-        )?;
+        self.pacer.wait();
 
-        // Wait for operation to complete so we don't confuse the device
-        // IMPORTANT NOTE: THIS IS A LOAD BEARING DELAY
-        self.delay(t);
+        self.bulk(TransferCommand::Write, Source{header: &cmd, data: buff, len: cmd.len() + buff.len()}, None, 0)?;
 
-        //self.delay(Duration::from_millis(1));
+        // The write completes once data is buffered by the device, so mark the bus
+        // busy until it has been clocked out to avoid confusing subsequent operations
+        self.pacer.busy(t);
 
         trace!("SPI write done");
 
         Ok(())
     }
 
-    fn delay(&mut self, d: Duration) {
-        let n = SystemTime::now();
-        while n.elapsed().unwrap() < d {}
-    }
-
-    // Transfer (write-read) to and from the SPI device
-    pub(crate) fn spi_write_read(&mut self, buff_out: &[u8], buff_in: &mut [u8]) -> Result<usize, Error> {
-
-        let mut cmd = vec![0u8; buff_out.len() + 8];
-
-        // TODO: split this into while loop so long packet writes work correctly
-        // At the moment the read buffer will probably be overwritten
-        cmd[2] = TransferCommand::WriteRead as u8;
-        LE::write_u32(&mut cmd[4..], buff_out.len() as u32);
-        (&mut cmd[8..]).copy_from_slice(buff_out);
+    /// Build a bulk transfer command header
+    fn transfer_header(command: TransferCommand, len: usize) -> Result<[u8; 8], Error> {
+        if len > u32::MAX as usize {
+            return Err(Error::TransferLength(len))
+        }
 
-        let total_time = self.spi_clock.transfer_time(buff_out.len() as u64);
-        trace!("SPI transfer (cmd: {:?} time: {} us)", cmd, total_time.as_micros());
+        let mut cmd = [0u8; 8];
+        cmd[2] = command as u8;
+        LE::write_u32(&mut cmd[4..], len as u32);
 
-        self.handle.write_bulk(
-            self.endpoints.write.address,
-            &cmd,
-            Duration::from_millis(200),
-        )?;
+        Ok(cmd)
+    }
 
-        trace!("SPI transfer await resp");
+    /// Operation context for an SPI transfer on the active channel
+    fn spi_op(&self, cmd: TransferCommand) -> Op {
+        Op::Spi(cmd, self.spi_active.as_ref().map(|(c, _)| *c))
+    }
 
-        let mut index = 0;
+    /// Timeout for each queued bulk transfer, covering transfers queued ahead of it
+    fn transfer_timeout(&self) -> Duration {
+        self.policy.bulk_timeout.saturating_add(self.spi_clock.transfer_time(self.transport.capacity() as u64))
+    }
 
-        while index < buff_in.len() {
-            let remainder = if buff_in.len() > index + 64 {
-                64
-            } else {
-                buff_in.len() - index
+    /// Run a bulk command, expecting `in_len` bytes to be read back.
+    ///
+    /// Failures are retried as per the transfer policy where nothing reached the device,
+    /// otherwise the device is re-synchronised before the error is returned so no partial
+    /// command is left in the device FIFO.
+    fn bulk(&mut self, cmd: TransferCommand, source: Source, mut buff: Option<&mut [u8]>, in_len: usize) -> Result<usize, Error> {
+        let mut attempt = 0;
+
+        loop {
+            let timeout = self.transfer_timeout();
+            let sink = buff.as_deref_mut().map(|b| Sink{buff: b, len: in_len});
+
+            let e = match self.transport.run(Some(source), sink, timeout) {
+                Ok(n) => return Ok(n),
+                Err(e) => e.with_op(self.spi_op(cmd)),
             };
 
-            let t = self.spi_clock.transfer_time(buff_out.len() as u64);
-            
-            trace!("SPI read (len: {}, index: {}, rem: {}, time: {} us)", 
-                    buff_in.len(), index, remainder, t.as_micros());
+            let (written, read) = self.transport.progress();
+            warn!("Bulk transfer failed ({:?}, wrote {} of {}, read {} of {} bytes)", e, written, source.len, read, in_len);
 
-            let n = self.handle.read_bulk(
-                self.endpoints.read.address,
-                &mut buff_in[index..index+remainder],
-                Duration::from_millis(200),
-            )?;
+            self.resync(source.len, in_len, written, read)?;
 
-            index += n;
+            if written == 0 && attempt < self.policy.retries && TransferPolicy::recoverable(&e) {
+                let backoff = self.policy.backoff(attempt);
+                debug!("Retrying bulk transfer in {:?}", backoff);
+                std::thread::sleep(backoff);
+                attempt += 1;
+                continue;
+            }
 
-            // Wait for operation to complete before we continue
-            self.delay(t);
+            return Err(e)
         }
+    }
 
-        trace!("SPI transfer done");
+    /// Re-synchronise with the device following a failed bulk command of `len` bytes,
+    /// falling back to a USB reset if the device can not be recovered
+    fn resync(&mut self, len: usize, in_len: usize, written: usize, read: usize) -> Result<(), Error> {
+        // A partial command can not be completed without clocking out data the caller did not supply
+        if written > 0 && written < len {
+            warn!("Bulk command partially written ({} of {} bytes), resetting device", written, len);
+            return self.reset_usb()
+        }
 
-        Ok(index)
-    }
+        if let Err(e) = self.resync_bulk(in_len, written, read) {
+            warn!("Bulk re-sync failed ({:?}), resetting device", e);
+            self.reset_usb()?;
+        }
 
-    /// Fetch the CP2130 chip version
-    pub(crate) fn version(&mut self) -> Result<u16, Error> {
-        let mut buff = [0u8; 2];
+        Ok(())
+    }
 
-        self.handle.read_control(
-            (RequestType::DEVICE_TO_HOST | RequestType::TYPE_VENDOR).bits(), 
-            Commands::GetReadOnlyVersion as u8,
-            0, 0,
-            &mut buff,
-            Duration::from_millis(200)
-        )?;
+    /// Reset the USB device, dropping any cached SPI configuration
+    fn reset_usb(&mut self) -> Result<(), Error> {
+        self.transport.reset().map_err(|e| Error::usb(Op::Device, e))?;
 
-        let version = LE::read_u16(&buff);
+        // SPI configuration is lost on reset, so must be re-applied
+        self.spi_active = None;
+        self.pacer.idle();
 
-        Ok(version)
+        Ok(())
     }
 
-    /// Set the mode and level for a given GPIO pin
-    pub(crate) fn set_gpio_mode_level(&mut self, pin: u8, mode: GpioMode, level: GpioLevel) -> Result<(), Error> {
-        assert!(pin <= 10);
-        
-        let cmd = [
-            pin,
-            mode as u8,
-            level as u8,
-        ];
+    /// Clear endpoint halts, then discard any read data pending from a fully written command
+    fn resync_bulk(&mut self, in_len: usize, written: usize, read: usize) -> Result<(), Error> {
+        let timeout = self.transfer_timeout();
 
-        trace!("GPIO set pin: {} mode: {:?} level: {:?} (cmd: {:?})", pin, mode, level, cmd);
+        self.transport.clear_halt(Direction::Out).map_err(|e| Error::usb(Op::Bulk, e))?;
+        self.transport.clear_halt(Direction::In).map_err(|e| Error::usb(Op::Bulk, e))?;
 
-        self.handle.write_control(
-            (RequestType::HOST_TO_DEVICE | RequestType::TYPE_VENDOR).bits(), 
-            Commands::SetGpioModeAndLevel as u8,
-            0, 0,
-            &cmd,
-            Duration::from_millis(200)
-        )?;
+        if written > 0 && read < in_len {
+            debug!("Discarding pending bulk read ({} bytes)", in_len - read);
+
+            self.transport.run(None, Some(Sink{buff: &mut [], len: in_len - read}), timeout)?;
+        }
 
         Ok(())
     }
 
-    /// Fetch the values for all GPIO pins
-    pub(crate) fn get_gpio_values(&mut self) -> Result<GpioLevels, Error> {
-        let mut buff = [0u8; 2];
+    /// Transfer (write-read) to and from the SPI device.
+    ///
+    /// Bulk reads are queued alongside the writes so the device can stream data
+    /// back as it is clocked in. If `buff_in` and `buff_out` differ in length the
+    /// write is padded with zeros and excess read data discarded.
+    pub(crate) fn spi_write_read(&mut self, buff_out: &[u8], buff_in: &mut [u8]) -> Result<usize, Error> {
+        let len = buff_out.len().max(buff_in.len());
+        let cmd = Self::transfer_header(TransferCommand::WriteRead, len)?;
 
-        self.handle.read_control(
-            (RequestType::DEVICE_TO_HOST | RequestType::TYPE_VENDOR).bits(), 
-            Commands::GetGpioValues as u8,
-            0, 0,
-            &mut buff,
-            Duration::from_millis(200)
-        )?;
+        trace!("SPI transfer (cmd: {:?} time: {} us)", cmd, self.spi_clock.transfer_time(len as u64).as_micros());
 
-        // Inexplicably big endian here
-        let values = GpioLevels::from_bits_truncate(BE::read_u16(&buff));
+        self.pacer.wait();
 
-        trace!("GPIO get pins (values: {:?})", values);
+        let n = self.bulk(TransferCommand::WriteRead, Source{header: &cmd, data: buff_out, len: cmd.len() + len}, Some(&mut *buff_in), len)?;
 
-        Ok(values)
+        if n < len {
+            warn!("SPI transfer short read ({} of {} bytes)", n, len);
+            return Err(Error::ShortTransfer{op: self.spi_op(TransferCommand::WriteRead), actual: n, expected: len})
+        }
+
+        trace!("SPI transfer done");
+
+        Ok(buff_in.len())
     }
 
-    /// Fetch the value for a given GPIO pin
-    pub (crate) fn get_gpio_level(&mut self, pin: u8) -> Result<bool, Error> {
-        assert!(pin <= 10);
+    /// Execute a sequence of SPI operations with chip select asserted throughout.
+    ///
+    /// Operations are merged into a single bulk command so the hardware chip select
+    /// is held for the whole transaction. Transactions containing delays can not be
+    /// merged, so chip select is instead driven manually as a GPIO.
+    pub(crate) fn spi_transaction(&mut self, channel: u8, config: &SpiConfig, ops: &mut [Operation<'_, u8>]) -> Result<(), Error> {
+        if ops.iter().any(|o| matches!(o, Operation::DelayNs(_))) {
+            return self.spi_transaction_gpio(channel, config, ops)
+        }
 
-        let levels = self.get_gpio_values()?;
+        self.spi_select(channel, config)?;
 
-        let v = match pin {
-            0 => levels.contains(GpioLevels::GPIO_0),
-            1 => levels.contains(GpioLevels::GPIO_1),
-            2 => levels.contains(GpioLevels::GPIO_2),
-            3 => levels.contains(GpioLevels::GPIO_3),
-            4 => levels.contains(GpioLevels::GPIO_4),
-            5 => levels.contains(GpioLevels::GPIO_5),
-            6 => levels.contains(GpioLevels::GPIO_6),
-            7 => levels.contains(GpioLevels::GPIO_7),
-            8 => levels.contains(GpioLevels::GPIO_8),
-            9 => levels.contains(GpioLevels::GPIO_9),
-            10 => levels.contains(GpioLevels::GPIO_10),
-            _ => panic!("invalid pin {}", pin),
-        };
+        let (out, read) = Self::merge_operations(ops);
 
-        Ok(v)
+        trace!("SPI transaction ({} operations, {} bytes)", ops.len(), out.len());
+
+        if out.is_empty() {
+            return Ok(())
+        }
+
+        if !read {
+            return self.spi_write(&out)
+        }
+
+        let mut buff = vec![0u8; out.len()];
+        self.spi_write_read(&out, &mut buff)?;
+
+        Self::split_operations(ops, &buff);
+
+        Ok(())
+    }
+
+    /// Merge transaction operations into a single output stream, padding reads with zeros.
+    /// Returns the stream and whether any operation reads data.
+    pub(crate) fn merge_operations(ops: &[Operation<'_, u8>]) -> (Vec<u8>, bool) {
+        let mut out = vec![];
+        let mut read = false;
+
+        for o in ops.iter() {
+            let start = out.len();
+            match o {
+                Operation::Read(r) => {
+                    out.resize(start + r.len(), 0);
+                    read = true;
+                },
+                Operation::Write(w) => out.extend_from_slice(w),
+                Operation::Transfer(r, w) => {
+                    out.extend_from_slice(w);
+                    out.resize(start + r.len().max(w.len()), 0);
+                    read = true;
+                },
+                Operation::TransferInPlace(b) => {
+                    out.extend_from_slice(b);
+                    read = true;
+                },
+                Operation::DelayNs(_) => (),
+            }
+        }
+
+        (out, read)
     }
-}
 
+    /// Split data read by a merged transaction back out to the operation buffers
+    pub(crate) fn split_operations(ops: &mut [Operation<'_, u8>], buff: &[u8]) {
+        let mut offset = 0;
 
+        for o in ops.iter_mut() {
+            match o {
+                Operation::Read(r) => {
+                    r.copy_from_slice(&buff[offset..][..r.len()]);
+                    offset += r.len();
+                },
+                Operation::Write(w) => offset += w.len(),
+                Operation::Transfer(r, w) => {
+                    let n = r.len().max(w.len());
+                    r.copy_from_slice(&buff[offset..][..r.len()]);
+                    offset += n;
+                },
+                Operation::TransferInPlace(b) => {
+                    b.copy_from_slice(&buff[offset..][..b.len()]);
+                    offset += b.len();
+                },
+                Operation::DelayNs(_) => (),
+            }
+        }
+    }
+
+    /// Execute a sequence of SPI operations, driving the channel chip select as a GPIO
+    /// and applying the configured post-assert and pre-deassert delays in software
+    fn spi_transaction_gpio(&mut self, channel: u8, config: &SpiConfig, ops: &mut [Operation<'_, u8>]) -> Result<(), Error> {
+        let mut c = config.clone();
+        c.cs_mode = CsMode::Disabled;
+        self.spi_select(channel, &c)?;
+
+        debug!("SPI transaction with GPIO chip select ({} operations)", ops.len());
+
+        self.set_gpio_mode_level(channel, config.cs_pin_mode, GpioLevel::Low)?;
+        std::thread::sleep(config.delays.post_assert_delay());
+
+        let res = ops.iter_mut().try_for_each(|o| match o {
+            Operation::Read(r) => self.spi_write_read(&[], r).map(|_| ()),
+            Operation::Write(w) => self.spi_write(w),
+            Operation::Transfer(r, w) => self.spi_write_read(w, r).map(|_| ()),
+            Operation::TransferInPlace(b) => {
+                let out = b.to_vec();
+                self.spi_write_read(&out, b).map(|_| ())
+            },
+            Operation::DelayNs(ns) => {
+                self.pacer.wait();
+                std::thread::sleep(Duration::from_nanos(*ns as u64));
+                Ok(())
+            },
+        });
+
+        // Always de-assert chip select, once outstanding writes have been clocked out
+        self.pacer.wait();
+        std::thread::sleep(config.delays.pre_deassert_delay());
+        self.set_gpio_mode_level(channel, config.cs_pin_mode, GpioLevel::High)?;
+
+        res
+    }
+
+    /// Start an SPI command without waiting for completion, writing `out` and reading back
+    /// `in_len` bytes (or none for a write). `complete` is called from the transfer callback
+    /// with the data read or any error, and `spi_finish` must be called to wait for the command.
+    #[cfg(feature = "async")]
+    pub(crate) fn spi_start(&mut self, channel: u8, config: &SpiConfig, out: Vec<u8>, in_len: usize, complete: Complete) -> Option<PendingSpi> {
+        let len = out.len().max(in_len);
+        let (command, read_len) = match in_len {
+            0 => (TransferCommand::Write, 0),
+            // As for `spi_write_read`, the device returns a byte per byte clocked
+            _ => (TransferCommand::WriteRead, len),
+        };
+
+        let cmd = match self.spi_select(channel, config).and_then(|_| Self::transfer_header(command, len)) {
+            Ok(c) => c,
+            Err(e) => {
+                complete(Err(e));
+                return None
+            },
+        };
+
+        trace!("SPI start (cmd: {:?} time: {} us)", cmd, self.spi_clock.transfer_time(len as u64).as_micros());
+
+        self.pacer.wait();
+
+        let source = Source{header: &cmd, data: &out, len: cmd.len() + len};
+        let op = self.spi_op(command);
+        let timeout = self.transfer_timeout();
+
+        self.transport.start(source.to_vec(), read_len, timeout, Box::new(move |r| {
+            let r = r.map_err(|e| e.with_op(op)).and_then(|mut data| {
+                Error::check_len(op, data.len(), read_len)?;
+                data.truncate(in_len);
+                Ok(data)
+            });
+            complete(r)
+        }));
+
+        Some(PendingSpi{command, len: source.len, read_len, time: self.spi_clock.transfer_time(len as u64)})
+    }
+
+    /// Wait for a started SPI command, handling transfer events until the transport is idle.
+    /// On failure the device is re-synchronised, failed commands are not retried as the
+    /// caller has already been notified.
+    #[cfg(feature = "async")]
+    pub(crate) fn spi_finish(&mut self, pending: PendingSpi) {
+        while self.transport.busy() {
+            // Failures complete the command in progress
+            if let Err(e) = self.transport.handle_events() {
+                warn!("Error handling transfer events: {:?}", e);
+            }
+        }
+
+        let (written, read) = self.transport.progress();
+
+        if written < pending.len || read < pending.read_len {
+            warn!("Bulk transfer failed (wrote {} of {}, read {} of {} bytes)", written, pending.len, read, pending.read_len);
+
+            if let Err(e) = self.resync(pending.len, pending.read_len, written, read) {
+                error!("Failed to re-sync device: {:?}", e);
+            }
+            return
+        }
+
+        // As for `spi_write`, the bus is busy until buffered data is clocked out
+        if pending.command == TransferCommand::Write {
+            self.pacer.busy(pending.time);
+        }
+    }
+
+    /// Read from the SPI device, clocking data only while the slave asserts RTR on GPIO3.
+    /// If the read does not complete within the timeout it is aborted.
+    pub(crate) fn spi_read_rtr(&mut self, buff: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        let cmd = Self::transfer_header(TransferCommand::ReadWithRTR, buff.len())?;
+
+        trace!("SPI RTR read (cmd: {:?})", cmd);
+
+        self.pacer.wait();
+
+        self.bulk(TransferCommand::ReadWithRTR, Source{header: &cmd, data: &[], len: cmd.len()}, None, 0)?;
+
+        let deadline = Instant::now() + timeout;
+        let mut index = 0;
+
+        // Read a packet at a time so no data is lost if a read times out
+        while index < buff.len() {
+            let remainder = (buff.len() - index).min(PACKET_SIZE);
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+
+            debug!("SPI RTR read (i: {}, rem: {}, timeout: {:?})", index, remainder, remaining);
+
+            let res = match remaining.is_zero() {
+                true => Err(rusb::Error::Timeout),
+                false => self.transport.read_bulk(&mut buff[index..index+remainder], remaining),
+            };
+
+            match res {
+                Ok(n) => index += n,
+                Err(rusb::Error::Timeout) => {
+                    warn!("SPI RTR read timeout ({} of {} bytes), aborting", index, buff.len());
+                    self.recover_rtr(buff.len() - index)?;
+                    return Err(Error::RtrTimeout(index))
+                },
+                Err(e) => {
+                    warn!("SPI RTR read failed ({:?}), aborting", e);
+                    self.recover_rtr(buff.len() - index)?;
+                    return Err(Error::usb(self.spi_op(TransferCommand::ReadWithRTR), e))
+                },
+            }
+        }
+
+        trace!("SPI RTR read done");
+
+        Ok(index)
+    }
+
+    /// Abort a failed RTR read, clearing endpoint halts and discarding up to `pending` bytes
+    /// already clocked into the device, falling back to a USB reset if this fails
+    fn recover_rtr(&mut self, pending: usize) -> Result<(), Error> {
+        let res = self.abort_rtr()
+            .and_then(|_| self.transport.clear_halt(Direction::In).map_err(|e| Error::usb(Op::Bulk, e)))
+            .and_then(|_| self.drain_read(pending));
+
+        match res {
+            Ok(0) => (),
+            Ok(n) => debug!("Discarded {} bytes of pending RTR read data", n),
+            Err(e) => {
+                warn!("RTR read recovery failed ({:?}), resetting device", e);
+                self.reset_usb()?;
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Discard up to `max` bytes of pending bulk IN data, returning the number of bytes discarded
+    fn drain_read(&mut self, max: usize) -> Result<usize, Error> {
+        let mut buff = [0u8; PACKET_SIZE];
+        let mut n = 0;
+
+        while n < max {
+            match self.transport.read_bulk(&mut buff, DRAIN_TIMEOUT) {
+                Ok(0) | Err(rusb::Error::Timeout) => break,
+                Ok(m) => n += m,
+                Err(e) => return Err(Error::usb(Op::Bulk, e)),
+            }
+        }
+
+        Ok(n)
+    }
+
+    /// Abort an active RTR read, then re-enable RTR reads
+    pub(crate) fn abort_rtr(&mut self) -> Result<(), Error> {
+        self.set_rtr_state(RtrState::Stopped)?;
+        self.set_rtr_state(RtrState::Active)?;
+        Ok(())
+    }
+
+    /// Fetch the RTR state
+    pub(crate) fn get_rtr_state(&mut self) -> Result<RtrState, Error> {
+        let mut buff = [0u8; 1];
+
+        self.control_read_exact(Commands::GetRtrState, 0, 0, &mut buff)?;
+
+        let state = match buff[0] {
+            0x00 => RtrState::Active,
+            _ => RtrState::Stopped,
+        };
+
+        trace!("Get RTR state: {:?}", state);
+
+        Ok(state)
+    }
+
+    /// Set the RTR state, stopping or re-enabling RTR reads
+    pub(crate) fn set_rtr_state(&mut self, state: RtrState) -> Result<(), Error> {
+        debug!("Set RTR state: {:?}", state);
+
+        self.control_write(Commands::SetRtrStop, 0, 0, &[state as u8])?;
+
+        Ok(())
+    }
+
+    /// Issue a vendor IN control request, returning the number of bytes read
+    pub(crate) fn control_read(&mut self, cmd: Commands, value: u16, index: u16, buff: &mut [u8]) -> Result<usize, Error> {
+        let timeout = self.policy.control_timeout;
+
+        self.with_retries(cmd, |s| s.transport.read_control(cmd as u8, value, index, buff, timeout)
+            .map_err(|e| Error::usb(Op::Control(cmd), e)))
+    }
+
+    /// Issue a vendor IN control request, failing if the full response is not returned
+    pub(crate) fn control_read_exact(&mut self, cmd: Commands, value: u16, index: u16, buff: &mut [u8]) -> Result<(), Error> {
+        let n = self.control_read(cmd, value, index, buff)?;
+        Error::check_len(Op::Control(cmd), n, buff.len())
+    }
+
+    /// Issue a vendor OUT control request, returning the number of bytes written
+    pub(crate) fn control_write(&mut self, cmd: Commands, value: u16, index: u16, data: &[u8]) -> Result<usize, Error> {
+        self.with_retries(cmd, |s| s.control_write_once(cmd, value, index, data))
+    }
+
+    /// Issue a vendor OUT control request without retries, for requests that are unsafe
+    /// to repeat (ie. OTP writes, where the device may still be programming after a timeout)
+    pub(crate) fn control_write_once(&mut self, cmd: Commands, value: u16, index: u16, data: &[u8]) -> Result<usize, Error> {
+        self.pacer.wait();
+
+        self.transport.write_control(cmd as u8, value, index, data, self.policy.control_timeout)
+            .map_err(|e| Error::usb(Op::Control(cmd), e))
+    }
+
+    /// Execute a control request, retrying recoverable failures as per the transfer policy.
+    /// Control endpoint stalls are cleared by the next request so need no further recovery.
+    fn with_retries<T, F>(&mut self, cmd: Commands, mut f: F) -> Result<T, Error>
+    where
+        F: FnMut(&mut Self) -> Result<T, Error>,
+    {
+        let mut attempt = 0;
+        loop {
+            match f(self) {
+                Err(e) if attempt < self.policy.retries && TransferPolicy::recoverable(&e) => {
+                    let backoff = self.policy.backoff(attempt);
+                    warn!("Control request {:?} failed ({:?}), retrying in {:?}", cmd, e, backoff);
+                    std::thread::sleep(backoff);
+                    attempt += 1;
+                },
+                r => return r,
+            }
+        }
+    }
+
+    /// Fetch the CP2130 chip version
+    pub(crate) fn version(&mut self) -> Result<u16, Error> {
+        let mut buff = [0u8; 2];
+
+        self.control_read_exact(Commands::GetReadOnlyVersion, 0, 0, &mut buff)?;
+
+        let version = LE::read_u16(&buff);
+
+        Ok(version)
+    }
+
+    /// Set the mode and level for a given GPIO pin
+    pub(crate) fn set_gpio_mode_level(&mut self, pin: u8, mode: GpioMode, level: GpioLevel) -> Result<(), Error> {
+        if pin as usize >= GPIO_COUNT {
+            return Err(Error::InvalidPin(pin))
+        }
+
+        let cmd = [
+            pin,
+            mode as u8,
+            level as u8,
+        ];
+
+        trace!("GPIO set pin: {} mode: {:?} level: {:?} (cmd: {:?})", pin, mode, level, cmd);
+
+        self.control_write(Commands::SetGpioModeAndLevel, 0, 0, &cmd)
+            .map_err(|e| e.with_op(Op::Gpio(Commands::SetGpioModeAndLevel, pin)))?;
+
+        if let Some(l) = self.gpio_levels.as_mut() {
+            l.set_level(pin, level)?;
+        }
+
+        Ok(())
+    }
+
+    /// Fetch the values for all GPIO pins
+    pub(crate) fn get_gpio_values(&mut self) -> Result<GpioLevels, Error> {
+        let mut buff = [0u8; 2];
+
+        self.control_read_exact(Commands::GetGpioValues, 0, 0, &mut buff)?;
+
+        let values = GpioLevels::decode(&buff);
+
+        trace!("GPIO get pins (values: {:?})", values);
+
+        Ok(values)
+    }
+
+    /// Set the values for the GPIO pins selected by `mask` in a single request
+    pub(crate) fn set_gpio_values(&mut self, levels: GpioLevels, mask: GpioLevels) -> Result<(), Error> {
+        let mut cmd = [0u8; 4];
+        cmd[..2].copy_from_slice(&levels.encode());
+        cmd[2..].copy_from_slice(&mask.encode());
+
+        trace!("GPIO set pins (values: {:?} mask: {:?})", levels, mask);
+
+        self.control_write(Commands::SetGpioValues, 0, 0, &cmd)?;
+
+        if let Some(l) = self.gpio_levels.as_mut() {
+            *l = (*l & !mask) | (levels & mask);
+        }
+
+        Ok(())
+    }
+
+    /// Fetch the value for a given GPIO pin
+    pub (crate) fn get_gpio_level(&mut self, pin: u8) -> Result<bool, Error> {
+        let mask = GpioLevels::pin(pin)?;
+
+        let levels = self.get_gpio_values()?;
+
+        let v = levels.contains(mask);
+
+        Ok(v)
+    }
+
+    /// Refresh the cached output levels from the device, leaving them unknown if they can not be read
+    pub(crate) fn load_gpio_levels(&mut self) {
+        self.gpio_levels = match self.get_gpio_mode_and_level() {
+            Ok((_modes, levels)) => Some(levels),
+            Err(e) => {
+                warn!("Failed to read GPIO levels, these will be read on demand: {:?}", e);
+                None
+            },
+        };
+    }
+
+    /// Fetch the output level last written to a given GPIO pin, reading the pin if this is unknown
+    pub(crate) fn get_gpio_level_cached(&mut self, pin: u8) -> Result<GpioLevel, Error> {
+        match self.gpio_levels {
+            Some(l) => l.level(pin),
+            None => self.get_gpio_values()?.level(pin),
+        }
+    }
+
+    /// Fetch the CLKOUT clock divider
+    pub(crate) fn get_clock_divider(&mut self) -> Result<ClockFrequency, Error> {
+        let mut buff = [0u8; 1];
+
+        self.control_read_exact(Commands::GetClockDivider, 0, 0, &mut buff)?;
+
+        let f = ClockFrequency::decode(buff[0]);
+
+        trace!("Get clock divider: {:?}", f);
+
+        Ok(f)
+    }
+
+    /// Set the CLKOUT clock divider
+    pub(crate) fn set_clock_divider(&mut self, f: &ClockFrequency) -> Result<(), Error> {
+        debug!("Set clock divider: {:?}", f);
+
+        self.control_write(Commands::SetClockDivider, 0, 0, &[f.encode()])?;
+
+        Ok(())
+    }
+
+    /// Fetch the event counter mode and value
+    pub(crate) fn get_event_counter(&mut self) -> Result<EventCount, Error> {
+        let mut buff = [0u8; 3];
+
+        self.control_read_exact(Commands::GetEventCounter, 0, 0, &mut buff)?;
+
+        let c = EventCount{
+            mode: EventCounterMode::decode(buff[0]),
+            count: BE::read_u16(&buff[1..]),
+            overflow: buff[0] & EVENT_COUNTER_OVERFLOW != 0,
+        };
+
+        trace!("Get event counter: {:?}", c);
+
+        Ok(c)
+    }
+
+    /// Set the event counter mode and value, this also clears the overflow flag
+    pub(crate) fn set_event_counter(&mut self, mode: EventCounterMode, count: u16) -> Result<(), Error> {
+        let mut cmd = [mode as u8, 0, 0];
+        BE::write_u16(&mut cmd[1..], count);
+
+        debug!("Set event counter mode: {:?} count: {}", mode, count);
+
+        self.control_write(Commands::SetEventCOunter, 0, 0, &cmd)?;
+
+        Ok(())
+    }
+
+    /// Fetch the read FIFO full threshold
+    pub(crate) fn get_full_threshold(&mut self) -> Result<u8, Error> {
+        let mut buff = [0u8; 1];
+
+        self.control_read_exact(Commands::GetFullThreshold, 0, 0, &mut buff)?;
+
+        trace!("Get full threshold: {}", buff[0]);
+
+        Ok(buff[0])
+    }
+
+    /// Set the read FIFO full threshold
+    pub(crate) fn set_full_threshold(&mut self, threshold: u8) -> Result<(), Error> {
+        if threshold == 0 {
+            return Err(Error::InvalidThreshold(threshold))
+        }
+
+        debug!("Set full threshold: {}", threshold);
+
+        self.control_write(Commands::SetFullThreshold, 0, 0, &[threshold])?;
+
+        Ok(())
+    }
+
+    /// Fetch the SPI control words for all channels
+    pub(crate) fn get_spi_word(&mut self) -> Result<[(SpiClock, SpiMode, GpioMode); SPI_CHANNEL_COUNT], Error> {
+        let mut buff = [0u8; SPI_CHANNEL_COUNT];
+
+        self.control_read_exact(Commands::GetSpiWord, 0, 0, &mut buff)?;
+
+        trace!("Get SPI word: {:02x?}", buff);
+
+        let mut words = [(SpiClock::Clock12Mhz, MODE_0, GpioMode::OpenDrain); SPI_CHANNEL_COUNT];
+        for (w, f) in words.iter_mut().zip(buff.iter()) {
+            let phase = match f & (1 << 5) != 0 {
+                true => Phase::CaptureOnSecondTransition,
+                false => Phase::CaptureOnFirstTransition,
+            };
+            let polarity = match f & (1 << 4) != 0 {
+                true => Polarity::IdleHigh,
+                false => Polarity::IdleLow,
+            };
+            let cs_pin_mode = match f & (1 << 3) != 0 {
+                true => GpioMode::PushPull,
+                false => GpioMode::OpenDrain,
+            };
+
+            *w = (SpiClock::decode(*f), SpiMode{phase, polarity}, cs_pin_mode);
+        }
+
+        Ok(words)
+    }
+
+    /// Fetch the SPI delay configuration for a channel
+    pub(crate) fn get_spi_delay(&mut self, channel: u8) -> Result<SpiDelays, Error> {
+        let mut buff = [0u8; 8];
+
+        self.control_read_exact(Commands::GetSpiDelay, 0, channel as u16, &mut buff)
+            .map_err(|e| e.with_op(Op::Channel(Commands::GetSpiDelay, channel)))?;
+
+        let delays = SpiDelays {
+            mask: DelayMask::from_bits_truncate(buff[1]),
+            inter_byte: BE::read_u16(&buff[2..]),
+            post_assert: BE::read_u16(&buff[4..]),
+            pre_deassert: BE::read_u16(&buff[6..]),
+        };
+
+        trace!("Get SPI delay channel: {} delays: {:?}", channel, delays);
+
+        Ok(delays)
+    }
+
+    /// Fetch the chip select enable masks, returning the enabled channels and pins
+    pub(crate) fn get_gpio_chip_select(&mut self) -> Result<(u16, GpioLevels), Error> {
+        let mut buff = [0u8; 4];
+
+        self.control_read_exact(Commands::GetGpioChipSelect, 0, 0, &mut buff)?;
+
+        let (channels, pins) = Self::decode_chip_select(&buff);
+
+        trace!("Get GPIO chip select channels: 0x{:04x} pins: {:?}", channels, pins);
+
+        Ok((channels, pins))
+    }
+
+    /// Decode a Get GPIO Chip Select response, the channel enable mask has bit n set for channel n
+    /// while the pin enable mask uses the GPIO level layout
+    fn decode_chip_select(buff: &[u8; 4]) -> (u16, GpioLevels) {
+        let channels = BE::read_u16(&buff[0..]) & ((1 << SPI_CHANNEL_COUNT) - 1);
+        let pins = GpioLevels::decode(&buff[2..]);
+
+        (channels, pins)
+    }
+
+    /// Fetch the GPIO mode and level masks, returning the push-pull output and high level pins
+    pub(crate) fn get_gpio_mode_and_level(&mut self) -> Result<(GpioLevels, GpioLevels), Error> {
+        let mut buff = [0u8; 4];
+
+        self.control_read_exact(Commands::GetGpioModeAndLevel, 0, 0, &mut buff)?;
+
+        let modes = GpioLevels::decode(&buff[0..]);
+        let levels = GpioLevels::decode(&buff[2..]);
+
+        trace!("Get GPIO mode and level modes: {:?} levels: {:?}", modes, levels);
+
+        Ok((modes, levels))
+    }
+
+    /// Fetch a snapshot of the full device state
+    pub(crate) fn snapshot(&mut self) -> Result<DeviceState, Error> {
+        let version = self.version()?;
+        let words = self.get_spi_word()?;
+        let (cs_channels, cs_pins) = self.get_gpio_chip_select()?;
+        let (modes, levels) = self.get_gpio_mode_and_level()?;
+        let clock = self.get_clock_divider()?;
+
+        // Read the pin configuration from the device, the cached copy is only refreshed on reset
+        let pin_config = self.get_pin_config()?;
+
+        let mut spi = Vec::with_capacity(SPI_CHANNEL_COUNT);
+        for (i, (clock, spi_mode, cs_pin_mode)) in words.iter().enumerate() {
+            let cs_mode = match cs_channels & (1 << i) != 0 {
+                true => CsMode::Enabled,
+                false => CsMode::Disabled,
+            };
+
+            spi.push(SpiConfig{
+                clock: *clock,
+                spi_mode: *spi_mode,
+                cs_mode,
+                cs_pin_mode: *cs_pin_mode,
+                delays: self.get_spi_delay(i as u8)?,
+            });
+        }
+
+        let mut pins = Vec::with_capacity(GPIO_PINS.len());
+        for (i, p) in GPIO_PINS.iter().enumerate() {
+            pins.push(PinState{
+                function: pin_config.function(i as u8)?,
+                push_pull: modes.contains(*p),
+                level: levels.level(i as u8)?,
+            });
+        }
+
+        Ok(DeviceState{version, spi, cs_channels, cs_pins, pins, clock})
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::transport::fake::{FakeTransport, Request, BulkFault};
+    use crate::pacing::fake::FakeClock;
+
+    /// Create a device over a fake transport, retrying without backoff
+    pub(crate) fn fake() -> (Inner, FakeTransport) {
+        let t = FakeTransport::default();
+        let opts = UsbOptions{
+            pacing_margin_us: 0,
+            policy: TransferPolicy{backoff: Duration::from_secs(0), ..Default::default()},
+            ..Default::default()
+        };
+
+        let inner = Inner::with_transport(Box::new(t.clone()), &opts);
+        t.take_requests();
+
+        (inner, t)
+    }
+
+    fn write_cmd(data: &[u8]) -> Vec<u8> {
+        let mut v = Inner::transfer_header(TransferCommand::Write, data.len()).unwrap().to_vec();
+        v.extend_from_slice(data);
+        v
+    }
+
+    #[test]
+    fn bulk_retries_unsent_commands() {
+        let (mut inner, t) = fake();
+
+        t.push_bulk(Some(BulkFault{written: 0, read: 0, error: rusb::Error::Timeout}));
+        inner.spi_write(&[1, 2, 3]).unwrap();
+
+        assert_eq!(t.take_requests(), vec![
+            Request::Bulk(write_cmd(&[1, 2, 3]), 0),
+            Request::ClearHalt(Direction::Out),
+            Request::ClearHalt(Direction::In),
+            Request::Bulk(write_cmd(&[1, 2, 3]), 0),
+        ]);
+    }
+
+    #[test]
+    fn bulk_gives_up_after_retries() {
+        let (mut inner, t) = fake();
+
+        for _i in 0..=inner.policy.retries {
+            t.push_bulk(Some(BulkFault{written: 0, read: 0, error: rusb::Error::Timeout}));
+        }
+
+        assert!(matches!(inner.spi_write(&[1]), Err(Error::Timeout(_))));
+
+        let bulk = t.take_requests().iter().filter(|r| matches!(r, Request::Bulk(..))).count();
+        assert_eq!(bulk, inner.policy.retries as usize + 1);
+    }
+
+    #[test]
+    fn bulk_partial_write_resets() {
+        let (mut inner, t) = fake();
+        inner.spi_active = Some((0, SpiConfig::default()));
+
+        // Part of the command reached the device, so it can not be retried or completed
+        t.push_bulk(Some(BulkFault{written: 10, read: 0, error: rusb::Error::Timeout}));
+        assert!(matches!(inner.spi_write(&[0xAA; 16]), Err(Error::Timeout(_))));
+
+        assert_eq!(t.take_requests(), vec![
+            Request::Bulk(write_cmd(&[0xAA; 16]), 0),
+            Request::Reset,
+        ]);
+        assert_eq!(inner.spi_active, None);
+    }
+
+    #[test]
+    fn bulk_drains_pending_read() {
+        let (mut inner, t) = fake();
+
+        // The command was written but the read failed part way
+        t.push_bulk(Some(BulkFault{written: 12, read: 1, error: rusb::Error::Pipe}));
+        let mut buff = [0u8; 4];
+        assert!(matches!(inner.spi_write_read(&[1, 2, 3, 4], &mut buff), Err(Error::Stall(_))));
+
+        let reqs = t.take_requests();
+        assert_eq!(&reqs[1..], &[
+            Request::ClearHalt(Direction::Out),
+            Request::ClearHalt(Direction::In),
+            Request::Bulk(vec![], 3),
+        ]);
+    }
+
+    #[test]
+    fn bulk_resync_falls_back_to_reset() {
+        let (mut inner, t) = fake();
+
+        t.set_clear_halt_fault(Some(rusb::Error::NoDevice));
+        t.push_bulk(Some(BulkFault{written: 0, read: 0, error: rusb::Error::Io}));
+        inner.spi_write(&[1]).unwrap();
+
+        assert_eq!(t.take_requests(), vec![
+            Request::Bulk(write_cmd(&[1]), 0),
+            Request::ClearHalt(Direction::Out),
+            Request::Reset,
+            Request::Bulk(write_cmd(&[1]), 0),
+        ]);
+    }
+
+    #[test]
+    fn control_retries() {
+        let (mut inner, t) = fake();
+
+        t.set_response(Commands::GetReadOnlyVersion as u8, &[0x11, 0x00]);
+        t.push_control_fault(rusb::Error::Timeout);
+
+        assert_eq!(inner.version().unwrap(), 0x0011);
+        assert_eq!(t.take_requests(), vec![
+            Request::ControlRead(Commands::GetReadOnlyVersion as u8, 0, 0),
+            Request::ControlRead(Commands::GetReadOnlyVersion as u8, 0, 0),
+        ]);
+    }
+
+    #[test]
+    fn rtr_timeout_recovers() {
+        let (mut inner, t) = fake();
+
+        t.push_read_bulk(Ok(vec![0x55; PACKET_SIZE]));
+
+        let mut buff = [0u8; 2 * PACKET_SIZE];
+        assert!(matches!(inner.spi_read_rtr(&mut buff, Duration::from_millis(10)), Err(Error::RtrTimeout(PACKET_SIZE))));
+        assert_eq!(buff[..PACKET_SIZE], [0x55; PACKET_SIZE]);
+
+        // RTR reads are aborted and any data already clocked in is discarded
+        let reqs = t.take_requests();
+        assert_eq!(&reqs[3..], &[
+            Request::ControlWrite(Commands::SetRtrStop as u8, 0, 0, vec![RtrState::Stopped as u8]),
+            Request::ControlWrite(Commands::SetRtrStop as u8, 0, 0, vec![RtrState::Active as u8]),
+            Request::ClearHalt(Direction::In),
+            Request::ReadBulk(PACKET_SIZE),
+        ]);
+    }
+
+    #[test]
+    fn spi_write_paces_following_requests() {
+        let (mut inner, _t) = fake();
+        let clock = FakeClock::new();
+        inner.pacer.set_clock(clock.clone());
+
+        // Following requests wait for buffered writes to be clocked out
+        inner.spi_write(&[0u8; 1200]).unwrap();
+        inner.set_gpio_mode_level(2, GpioMode::PushPull, GpioLevel::High).unwrap();
+        assert_eq!(clock.sleeps(), vec![SpiClock::Clock12Mhz.transfer_time(1200)]);
+
+        // Unless the bus has since gone idle
+        inner.spi_write(&[0u8; 1200]).unwrap();
+        clock.advance(Duration::from_millis(1));
+        inner.set_gpio_mode_level(2, GpioMode::PushPull, GpioLevel::Low).unwrap();
+        assert_eq!(clock.sleeps(), vec![]);
+
+        // Reads wait for pending writes, but complete once data has been clocked in so leave the bus idle
+        let mut buff = [0u8; 1200];
+        inner.spi_write(&[0u8; 1200]).unwrap();
+        inner.spi_write_read(&[0u8; 1200], &mut buff).unwrap();
+        inner.spi_read(&mut buff).unwrap();
+        assert_eq!(clock.sleeps(), vec![SpiClock::Clock12Mhz.transfer_time(1200)]);
+    }
+
+    #[test]
+    fn reset_clears_state() {
+        let (mut inner, t) = fake();
+
+        inner.spi_select(1, &SpiConfig::default()).unwrap();
+        inner.set_gpio_mode_level(2, GpioMode::PushPull, GpioLevel::High).unwrap();
+        // Output levels are restored to their power-on values
+        t.set_response(Commands::GetGpioModeAndLevel as u8, &[0x00, 0x28, 0x00, 0x08]);
+        inner.reset().unwrap();
+
+        assert_eq!(inner.spi_active, None);
+        assert_eq!(inner.gpio_levels, Some(GpioLevels::GPIO_0));
+
+        // Levels are read on demand if they can not be seeded
+        t.push_control_fault(rusb::Error::NoDevice);
+        inner.load_gpio_levels();
+        assert_eq!(inner.gpio_levels, None);
+        assert_eq!(inner.get_gpio_level_cached(0).unwrap(), GpioLevel::Low);
+
+        // Channel configuration must be re-applied following a reset
+        t.take_requests();
+        inner.spi_select(1, &SpiConfig::default()).unwrap();
+        assert!(t.take_requests().iter().any(|r| matches!(r, Request::ControlWrite(c, ..) if *c == Commands::SetSpiWord as u8)));
+    }
+
+    #[test]
+    fn pin_config_reread_when_unknown() {
+        let (mut inner, t) = fake();
+
+        let mut config = PinConfig::default();
+        config.set_function(3, PinFunction::Rtr).unwrap();
+        t.set_response(Commands::GetPinConfig as u8, &config.encode());
+
+        // Pin functions can not be checked while the configuration is unknown
+        t.push_control_fault(rusb::Error::NoDevice);
+        inner.load_pin_config();
+        assert_eq!(inner.pin_config, None);
+
+        t.push_control_fault(rusb::Error::NoDevice);
+        assert!(matches!(inner.pin_function(3), Err(Error::Disconnected(_))));
+
+        // The configuration is re-read once the device is back
+        assert_eq!(inner.pin_function(3).unwrap(), PinFunction::Rtr);
+        assert_eq!(inner.pin_config, Some(config));
+    }
+
+    #[test]
+    fn spi_channel_switch() {
+        let (mut inner, t) = fake();
+        let config = SpiConfig{cs_mode: CsMode::Enabled, ..Default::default()};
+
+        inner.spi_select(0, &config).unwrap();
+        t.take_requests();
+
+        // Switching channels configures the new channel without touching the previous one
+        inner.spi_select(1, &config).unwrap();
+
+        let reqs = t.take_requests();
+        assert!(!reqs.contains(&Request::ControlWrite(Commands::SetGpioChipSelect as u8, 0, 0, vec![0, CsMode::Disabled as u8])));
+        assert!(reqs.iter().all(|r| matches!(r, Request::ControlWrite(_, _, _, d) if d[0] == 1)));
+
+        // Releasing a channel still disables its chip select, even when not active
+        inner.spi_release(0).unwrap();
+        assert_eq!(t.take_requests(), vec![
+            Request::ControlWrite(Commands::SetGpioChipSelect as u8, 0, 0, vec![0, CsMode::Disabled as u8]),
+        ]);
+
+        // Selecting the same configuration again requires no requests
+        inner.spi_select(1, &config).unwrap();
+        assert_eq!(t.take_requests(), vec![]);
+    }
+
+    #[test]
+    fn gpio_levels_seeded() {
+        let t = FakeTransport::default();
+        t.set_response(Commands::GetGpioModeAndLevel as u8, &[0x00, 0x28, 0x00, 0x20]);
+
+        let mut inner = Inner::with_transport(Box::new(t.clone()), &UsbOptions::default());
+
+        assert_eq!(inner.get_gpio_level_cached(2).unwrap(), GpioLevel::High);
+        assert_eq!(inner.get_gpio_level_cached(0).unwrap(), GpioLevel::Low);
+    }
+
+    /// Fetch the bulk commands issued to the transport
+    fn bulk_requests(t: &FakeTransport) -> Vec<Request> {
+        t.take_requests().into_iter().filter(|r| matches!(r, Request::Bulk(..))).collect()
+    }
+
+    #[test]
+    fn spi_transaction_merges_operations() {
+        let (mut inner, t) = fake();
+
+        let mut read = [0xFFu8; 2];
+        let mut transfer = [0u8; 2];
+        let mut in_place = [7u8, 8];
+        let mut ops = [
+            Operation::Write(&[1, 2]),
+            Operation::Read(&mut read),
+            Operation::Transfer(&mut transfer, &[5, 6, 9]),
+            Operation::TransferInPlace(&mut in_place),
+        ];
+        inner.spi_transaction(1, &SpiConfig::default(), &mut ops).unwrap();
+
+        // Operations are issued as a single command, padding reads with zeros
+        let data = [1, 2, 0, 0, 5, 6, 9, 7, 8];
+        let mut cmd = Inner::transfer_header(TransferCommand::WriteRead, data.len()).unwrap().to_vec();
+        cmd.extend_from_slice(&data);
+        assert_eq!(bulk_requests(&t), vec![Request::Bulk(cmd, data.len())]);
+
+        // Read data is split back out to each operation (the fake loops back written data)
+        assert_eq!(read, [0, 0]);
+        assert_eq!(transfer, [5, 6]);
+        assert_eq!(in_place, [7, 8]);
+    }
+
+    #[test]
+    fn spi_transaction_write_only() {
+        let (mut inner, t) = fake();
+
+        let mut ops = [Operation::Write(&[1]), Operation::Write(&[2, 3])];
+        inner.spi_transaction(0, &SpiConfig::default(), &mut ops).unwrap();
+        assert_eq!(bulk_requests(&t), vec![Request::Bulk(write_cmd(&[1, 2, 3]), 0)]);
+
+        // Empty transactions do not issue a command
+        inner.spi_transaction(0, &SpiConfig::default(), &mut []).unwrap();
+        assert_eq!(bulk_requests(&t), vec![]);
+    }
+
+    #[test]
+    fn spi_transaction_with_delay() {
+        let (mut inner, t) = fake();
+
+        let config = SpiConfig::default();
+        let mut read = [0u8; 1];
+        let mut ops = [Operation::Write(&[1]), Operation::DelayNs(10), Operation::Read(&mut read)];
+        inner.spi_transaction(2, &config, &mut ops).unwrap();
+
+        // Chip select is driven as a GPIO around separate commands for each operation
+        let gpio = |level: GpioLevel| Request::ControlWrite(Commands::SetGpioModeAndLevel as u8, 0, 0, vec![2, config.cs_pin_mode as u8, level as u8]);
+        let reqs: Vec<_> = t.take_requests().into_iter()
+            .filter(|r| matches!(r, Request::Bulk(..)) || *r == gpio(GpioLevel::Low) || *r == gpio(GpioLevel::High))
+            .collect();
+
+        let mut read_cmd = Inner::transfer_header(TransferCommand::WriteRead, 1).unwrap().to_vec();
+        read_cmd.push(0);
+        assert_eq!(reqs, vec![
+            gpio(GpioLevel::Low),
+            Request::Bulk(write_cmd(&[1]), 0),
+            Request::Bulk(read_cmd, 1),
+            gpio(GpioLevel::High),
+        ]);
+
+        // Hardware chip select is left disabled for the channel
+        assert!(matches!(&inner.spi_active, Some((2, p)) if p.cs_mode == CsMode::Disabled));
+
+        // Until the next transaction without delays
+        inner.spi_transaction(2, &config, &mut [Operation::Write(&[1])]).unwrap();
+        assert!(matches!(&inner.spi_active, Some((2, p)) if *p == config));
+    }
+
+    #[test]
+    fn decode_chip_select() {
+        // Channels 0 and 2 enabled, driving chip select on GPIO.0 and GPIO.2
+        let (channels, pins) = Inner::decode_chip_select(&[0x00, 0x05, 0x00, 0x28]);
+
+        assert_eq!(channels, (1 << 0) | (1 << 2));
+        assert_eq!(pins, GpioLevels::GPIO_0 | GpioLevels::GPIO_2);
+    }
+
+    #[test]
+    fn clock_frequency() {
+        // Exact dividers
+        let f = ClockFrequency::for_target(12_000_000).unwrap();
+        assert_eq!(f.divider, 2);
+        assert_eq!(f.frequency, 12_000_000);
+        assert_eq!(f.error, 0);
+
+        // Nearest divider with error reported
+        let f = ClockFrequency::for_target(1_000_000).unwrap();
+        assert_eq!(f.divider, 24);
+        let f = ClockFrequency::for_target(7_000_000).unwrap();
+        assert_eq!(f.divider, 3);
+        assert_eq!(f.error, 1_000_000);
+
+        // Limits
+        assert_eq!(ClockFrequency::for_target(93_750).unwrap().divider, 256);
+        assert!(ClockFrequency::for_target(24_000_001).is_err());
+        assert!(ClockFrequency::for_target(1_000).is_err());
+        assert!(ClockFrequency::from_divider(0).is_err());
+        assert!(ClockFrequency::from_divider(257).is_err());
+    }
+
+    #[test]
+    fn gpio_levels() {
+        // Pin indices map over the gap in the device bit layout
+        let l = GpioLevels::from_pins(&[0, 4, 5, 6, 10]).unwrap();
+        assert_eq!(l.pins(), vec![0, 4, 5, 6, 10]);
+        assert_eq!(GpioLevels::pin(5).unwrap(), GpioLevels::GPIO_5);
+        assert_eq!(GpioLevels::pin(6).unwrap(), GpioLevels::GPIO_6);
+
+        let mut l = GpioLevels::empty();
+        l.set_level(7, GpioLevel::High).unwrap();
+        assert_eq!(l.level(7).unwrap(), GpioLevel::High);
+        assert_eq!(l.level(8).unwrap(), GpioLevel::Low);
+
+        // Pins outside the valid range are rejected
+        assert!(GpioLevels::pin(11).is_err());
+        assert!(GpioLevels::from_pins(&[1, 11]).is_err());
+    }
+
+    #[test]
+    fn gpio_pin() {
+        use std::convert::TryFrom;
+
+        assert_eq!(GpioPin::try_from(6).unwrap(), GpioPin::Gpio6);
+        assert!(matches!(GpioPin::try_from(11), Err(Error::InvalidPin(11))));
+        assert_eq!("gpio10".parse::<GpioPin>().unwrap(), GpioPin::Gpio10);
+        assert!("12".parse::<GpioPin>().is_err());
+
+        assert_eq!(GpioPin::all().len(), 11);
+        assert_eq!(GpioLevels::from(GpioPin::Gpio5), GpioLevels::GPIO_5);
+    }
+
+    #[test]
+    fn transfer_policy() {
+        let p = TransferPolicy{backoff: Duration::from_millis(5), ..Default::default()};
+
+        // Backoff doubles on each retry
+        assert_eq!(p.backoff(0), Duration::from_millis(5));
+        assert_eq!(p.backoff(1), Duration::from_millis(10));
+        assert_eq!(p.backoff(3), Duration::from_millis(40));
+
+        // Transient failures are retried, disconnects are not
+        assert!(TransferPolicy::recoverable(&Error::usb(Op::Device, rusb::Error::Timeout)));
+        assert!(TransferPolicy::recoverable(&Error::usb(Op::Device, rusb::Error::Pipe)));
+        assert!(!TransferPolicy::recoverable(&Error::usb(Op::Device, rusb::Error::NoDevice)));
+        assert!(!TransferPolicy::recoverable(&Error::InvalidPin(12)));
+    }
+
+    #[test]
+    fn spi_delays() {
+        let mut d = SpiDelays{post_assert: 3, pre_deassert: 5, ..Default::default()};
+
+        // Delays only apply when enabled in the mask
+        assert_eq!(d.post_assert_delay(), Duration::from_secs(0));
+        assert_eq!(d.pre_deassert_delay(), Duration::from_secs(0));
+
+        // Delays are in units of 10 us
+        d.mask = DelayMask::POST_ASSERT | DelayMask::PRE_DEASSERT;
+        assert_eq!(d.post_assert_delay(), Duration::from_micros(30));
+        assert_eq!(d.pre_deassert_delay(), Duration::from_micros(50));
+    }
+}
@@ -0,0 +1,104 @@
+//! CP2130 embedded-hal 0.2 trait implementations
+//!
+//! These allow drivers built on the 0.2 blocking traits to be used directly,
+//! enabled with the `eh0` feature.
+//!
+//! Copyright 2019 Ryan Kurte
+
+use embedded_hal::spi::Operation;
+use embedded_hal_02::blocking::spi;
+use embedded_hal_02::digital::v2 as digital;
+
+use crate::{Error, GpioLevel, InputPin, OutputPin, Spi, SpiDevice};
+
+impl spi::Transfer<u8> for Spi {
+    type Error = Error;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        let out = words.to_vec();
+        self.select()?.spi_write_read(&out, words)?;
+        Ok(words)
+    }
+}
+
+impl spi::Write<u8> for Spi {
+    type Error = Error;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        self.select()?.spi_write(words)
+    }
+}
+
+impl spi::Transfer<u8> for SpiDevice {
+    type Error = Error;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        self.transaction(&mut [Operation::TransferInPlace(&mut *words)])?;
+        Ok(words)
+    }
+}
+
+impl spi::Write<u8> for SpiDevice {
+    type Error = Error;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        self.transaction(&mut [Operation::Write(words)])
+    }
+}
+
+/// Operations are executed with chip select held asserted, as per `SpiDevice::transaction`
+impl spi::Transactional<u8> for SpiDevice {
+    type Error = Error;
+
+    fn exec(&mut self, operations: &mut [spi::Operation<'_, u8>]) -> Result<(), Self::Error> {
+        let mut ops: Vec<_> = operations.iter_mut().map(|o| match o {
+            spi::Operation::Write(w) => Operation::Write(w),
+            spi::Operation::Transfer(b) => Operation::TransferInPlace(b),
+        }).collect();
+
+        self.transaction(&mut ops)
+    }
+}
+
+impl digital::InputPin for InputPin {
+    type Error = Error;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        self.handle.level()
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        let v = self.handle.level()?;
+        Ok(!v)
+    }
+}
+
+impl digital::OutputPin for OutputPin {
+    type Error = Error;
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.set_level(GpioLevel::High)
+    }
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.set_level(GpioLevel::Low)
+    }
+}
+
+impl digital::StatefulOutputPin for OutputPin {
+    fn is_set_high(&self) -> Result<bool, Self::Error> {
+        Ok(self.level()? == GpioLevel::High)
+    }
+
+    fn is_set_low(&self) -> Result<bool, Self::Error> {
+        Ok(self.level()? == GpioLevel::Low)
+    }
+}
+
+impl digital::ToggleableOutputPin for OutputPin {
+    type Error = Error;
+
+    fn toggle(&mut self) -> Result<(), Self::Error> {
+        OutputPin::toggle(self)
+    }
+}
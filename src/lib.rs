@@ -3,7 +3,10 @@
 //! 
 //! Copyright 2019 Ryan Kurte
 
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::convert::TryFrom;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
 #[macro_use]
 extern crate log;
@@ -11,48 +14,232 @@ extern crate log;
 #[macro_use]
 extern crate lazy_static;
 
-use failure::Fail;
-
 pub use embedded_hal::spi::{Mode as SpiMode};
 use rusb::{Device as UsbDevice, Context as UsbContext, DeviceDescriptor};
 
 pub mod device;
 pub mod manager;
+pub mod otp;
+pub mod pacing;
+pub mod watch;
+#[cfg(feature = "async")]
+pub mod asynch;
+mod transfer;
+mod transport;
+#[cfg(feature = "eh0")]
+mod eh0;
 pub mod prelude;
 
-pub use crate::device::{UsbOptions, GpioMode, GpioLevel, SpiConfig, SpiClock};
+pub use crate::device::{UsbOptions, TransferPolicy, GpioMode, GpioLevel, GpioLevels, GpioPin, SpiConfig, SpiDelays, DelayMask, CsMode, SpiClock, EventCounterMode, EventCount, ClockFrequency, RtrState, DeviceState, PinState};
+pub use crate::pacing::{Clock, SystemClock, Pacer};
+pub use crate::watch::{WatchOptions, GpioWatcher, GpioEvent, Edge, Debouncer, VcdWriter};
+#[cfg(feature = "async")]
+pub use crate::asynch::{AsyncCp2130, AsyncSpi, AsyncSpiDevice, AsyncInputPin, AsyncDelay};
+pub use crate::otp::{PromConfig, UsbConfig, UsbConfigMask, MAX_POWER_LIMIT_MA, PowerMode, TransferPriority, UsbString, LockByte, LockConfirmation, PinConfig, PinFunction};
 use crate::device::*;
 
 
-#[derive(Debug, Fail)]
-pub enum Error {
-//    Io(IoError),
-    #[fail(display = "USB error: {:?}", 0)]
-    Usb(rusb::Error),
+/// Operation in progress when an error occurred
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    /// USB device access (enumeration, configuration, recovery)
+    Device,
+    /// Vendor control request
+    Control(Commands),
+    /// Vendor control request for a GPIO pin
+    Gpio(Commands, u8),
+    /// Vendor control request for an SPI channel
+    Channel(Commands, u8),
+    /// Bulk SPI transfer, on the active channel if known
+    Spi(TransferCommand, Option<u8>),
+    /// Bulk transfer
+    Bulk,
+}
 
-    #[fail(display = "No matching endpoint languages found")]
+impl std::fmt::Display for Op {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Op::Device => write!(f, "device access"),
+            Op::Control(c) => write!(f, "{:?} request", c),
+            Op::Gpio(c, p) => write!(f, "{:?} request (GPIO {})", c, p),
+            Op::Channel(c, ch) => write!(f, "{:?} request (channel {})", c, ch),
+            Op::Spi(t, Some(ch)) => write!(f, "SPI {:?} (channel {})", t, ch),
+            Op::Spi(t, None) => write!(f, "SPI {:?}", t),
+            Op::Bulk => write!(f, "bulk transfer"),
+        }
+    }
+}
+
+/// Error categories, for deciding how to handle an error
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    /// The device or slave did not respond in time
+    Timeout,
+    /// The device has been disconnected
+    Disconnected,
+    /// A USB endpoint stalled
+    Stall,
+    /// Insufficient permissions to access the device
+    Permission,
+    /// Fewer bytes were transferred than expected
+    ShortTransfer,
+    /// An argument was out of range or otherwise invalid
+    InvalidArgument,
+    /// The pin or resource is already in use or assigned another function
+    InUse,
+    /// The OTP field is locked
+    Locked,
+    /// The device returned an unexpected response
+    Device,
+    /// Other errors
+    Other,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("USB error during {op}: {source}")]
+    Usb{op: Op, source: rusb::Error},
+    #[error("Timeout during {0}")]
+    Timeout(Op),
+    #[error("Device disconnected during {0}")]
+    Disconnected(Op),
+    #[error("Endpoint stalled during {0}")]
+    Stall(Op),
+    #[error("Permission denied during {0}")]
+    Permission(Op),
+    #[error("Short transfer during {op} ({actual} of {expected} bytes)")]
+    ShortTransfer{op: Op, actual: usize, expected: usize},
+
+    #[error("No matching endpoint languages found")]
     NoLanguages,
 
-    #[fail(display = "No valid endpoint configuration found")]
+    #[error("No valid endpoint configuration found")]
     Configurations,
-    #[fail(display = "No matching endpoint found")]
+    #[error("No matching endpoint found")]
     Endpoint,
-    #[fail(display = "GPIO pin already in use")]
-    GpioInUse,
-    #[fail(display = "Invalid SPI index")]
-    InvalidIndex,
-    #[fail(display = "Invalid SPI baud rate")]
+    #[error("GPIO pin {0} already in use")]
+    GpioInUse(u8),
+    #[error("Invalid device index ({0})")]
+    InvalidDevice(usize),
+    #[error("Invalid SPI channel ({0})")]
+    InvalidChannel(u8),
+    #[error("Invalid SPI baud rate")]
     InvalidBaud,
+    #[error("Invalid PROM length ({0} bytes)")]
+    PromLength(usize),
+    #[error("PROM verification failed (block {0})")]
+    PromVerify(u8),
+    #[error("PROM block {0} can not be written, OTP bits can not be set once cleared")]
+    PromUnwritable(u8),
+    #[error("Unexpected response from device")]
+    InvalidResponse,
+    #[error("String too long (max {0} characters)")]
+    StringLength(usize),
+    #[error("OTP field locked: {0:?}")]
+    Locked(LockByte),
+    #[error("Invalid GPIO pin ({0})")]
+    InvalidPin(u8),
+    #[error("GPIO pin {0} does not support function {1:?}")]
+    UnsupportedFunction(u8, PinFunction),
+    #[error("GPIO pin {0} is configured for {1:?}")]
+    GpioFunction(u8, PinFunction),
+    #[error("Invalid clock divider ({0})")]
+    InvalidDivider(u16),
+    #[error("Invalid clock frequency ({0} Hz)")]
+    InvalidFrequency(u32),
+    #[error("Event counter overflow")]
+    CounterOverflow,
+    #[error("Timeout waiting for RTR read ({0} bytes read)")]
+    RtrTimeout(usize),
+    #[error("Invalid maximum bus current ({0} mA, at most 510 mA)")]
+    InvalidMaxPower(u16),
+    #[error("Invalid sampling interval ({0:?})")]
+    InvalidInterval(Duration),
+    #[error("Invalid FIFO full threshold ({0})")]
+    InvalidThreshold(u8),
+    #[error("Transfer too long ({0} bytes)")]
+    TransferLength(usize),
+    #[error("Device state poisoned by a panic in another thread")]
+    Poisoned,
+}
+
+impl Error {
+    /// Build an error from a libusb error, categorised by cause
+    pub fn usb(op: Op, e: rusb::Error) -> Self {
+        match e {
+            rusb::Error::Timeout => Error::Timeout(op),
+            rusb::Error::NoDevice => Error::Disconnected(op),
+            rusb::Error::Pipe => Error::Stall(op),
+            rusb::Error::Access => Error::Permission(op),
+            _ => Error::Usb{op, source: e},
+        }
+    }
+
+    /// Attach the operation in progress to a transport error
+    pub fn with_op(self, op: Op) -> Self {
+        match self {
+            Error::Usb{source, ..} => Error::Usb{op, source},
+            Error::Timeout(_) => Error::Timeout(op),
+            Error::Disconnected(_) => Error::Disconnected(op),
+            Error::Stall(_) => Error::Stall(op),
+            Error::Permission(_) => Error::Permission(op),
+            Error::ShortTransfer{actual, expected, ..} => Error::ShortTransfer{op, actual, expected},
+            e => e,
+        }
+    }
+
+    /// Fetch the operation in progress for transport errors
+    pub fn op(&self) -> Option<Op> {
+        match self {
+            Error::Usb{op, ..} | Error::Timeout(op) | Error::Disconnected(op) |
+            Error::Stall(op) | Error::Permission(op) | Error::ShortTransfer{op, ..} => Some(*op),
+            _ => None,
+        }
+    }
+
+    /// Fetch the error category
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Timeout(_) | Error::RtrTimeout(_) => ErrorKind::Timeout,
+            Error::Disconnected(_) => ErrorKind::Disconnected,
+            Error::Stall(_) => ErrorKind::Stall,
+            Error::Permission(_) => ErrorKind::Permission,
+            Error::ShortTransfer{..} => ErrorKind::ShortTransfer,
+            Error::InvalidDevice(_) | Error::InvalidChannel(_) | Error::InvalidBaud |
+            Error::PromLength(_) | Error::PromUnwritable(_) | Error::StringLength(_) | Error::InvalidPin(_) |
+            Error::UnsupportedFunction(..) | Error::InvalidDivider(_) | Error::InvalidFrequency(_) |
+            Error::InvalidThreshold(_) | Error::InvalidMaxPower(_) | Error::InvalidInterval(_) | Error::TransferLength(_) => ErrorKind::InvalidArgument,
+            Error::GpioInUse(_) | Error::GpioFunction(..) => ErrorKind::InUse,
+            Error::Locked(_) => ErrorKind::Locked,
+            Error::InvalidResponse | Error::PromVerify(_) | Error::CounterOverflow => ErrorKind::Device,
+            _ => ErrorKind::Other,
+        }
+    }
+
+    /// Check a transfer moved the expected number of bytes
+    pub(crate) fn check_len(op: Op, actual: usize, expected: usize) -> Result<(), Self> {
+        match actual == expected {
+            true => Ok(()),
+            false => Err(Error::ShortTransfer{op, actual, expected}),
+        }
+    }
+}
+
+/// Lock the shared device state, failing if another thread panicked while holding it
+pub(crate) fn lock(inner: &Mutex<Inner>) -> Result<MutexGuard<'_, Inner>, Error> {
+    inner.lock().map_err(|_| Error::Poisoned)
 }
 
 impl From<rusb::Error> for Error {
     fn from(e: rusb::Error) -> Self {
-        Error::Usb(e)
+        Error::usb(Op::Device, e)
     }
 }
 
 
-
 /// CP2130 provides methods to interact with the device, as well as create new spi and gpio connectors.
 pub struct Cp2130 {
     inner: Arc<Mutex<Inner>>,
@@ -81,6 +268,9 @@ pub trait Device {
     
     /// Fetch the value for a given GPIO pin
     fn get_gpio_level(&self, pin: u8) -> Result<bool, Error>;
+
+    /// Set the values for the GPIO pins selected by `mask` in a single request
+    fn set_gpio_values(&self, levels: GpioLevels, mask: GpioLevels) -> Result<(), Error>;
 }
 
 impl Cp2130 {
@@ -100,46 +290,193 @@ impl Cp2130 {
         self.info.clone()
     }
 
+    /// Reset the device, re-reading the pin configuration so changes from `set_pin_config` apply
     pub fn reset(&self) -> Result<(), Error> {
-        self.inner.lock().unwrap().reset()
+        lock(&self.inner)?.reset()
+    }
+
+    /// Fetch the USB configuration (VID, PID, power, release version and transfer priority)
+    pub fn usb_config(&self) -> Result<UsbConfig, Error> {
+        lock(&self.inner)?.get_usb_config()
+    }
+
+    /// Write the USB configuration fields selected by `mask`, other fields are left unchanged.
+    /// Note that this memory is one-time-programmable.
+    pub fn set_usb_config(&self, config: &UsbConfig, mask: UsbConfigMask) -> Result<(), Error> {
+        lock(&self.inner)?.set_usb_config(config, mask)
+    }
+
+    /// Fetch a programmable USB string (manufacturer, product or serial)
+    pub fn usb_string(&self, field: UsbString) -> Result<String, Error> {
+        lock(&self.inner)?.get_usb_string(field)
     }
 
-    /// Create an SPI connector
+    /// Write a programmable USB string (manufacturer, product or serial).
+    /// This fails with `Error::Locked` if the field has already been locked.
+    /// Note that this memory is one-time-programmable.
+    pub fn set_usb_string(&self, field: UsbString, value: &str) -> Result<(), Error> {
+        lock(&self.inner)?.set_usb_string(field, value)
+    }
+
+    /// Fetch the lock byte, set fields are unlocked (still writable)
+    pub fn lock_byte(&self) -> Result<LockByte, Error> {
+        lock(&self.inner)?.get_lock_byte()
+    }
+
+    /// Permanently lock OTP fields, preventing any further writes.
+    /// This is irreversible and requires an explicit `LockConfirmation`.
+    pub fn lock(&self, confirm: LockConfirmation) -> Result<(), Error> {
+        lock(&self.inner)?.set_lock_byte(confirm)
+    }
+
+    /// Fetch the pin configuration (GPIO and alternate pin functions)
+    pub fn pin_config(&self) -> Result<PinConfig, Error> {
+        lock(&self.inner)?.get_pin_config()
+    }
+
+    /// Write the pin configuration, this takes effect on the next device reset.
+    /// Note that this memory is one-time-programmable.
+    pub fn set_pin_config(&self, config: &PinConfig) -> Result<(), Error> {
+        lock(&self.inner)?.set_pin_config(config)
+    }
+
+    /// Read the PROM (OTP configuration) image from the device
+    pub fn prom_config(&self) -> Result<PromConfig, Error> {
+        lock(&self.inner)?.get_prom_config()
+    }
+
+    /// Write a PROM (OTP configuration) image to the device.
+    ///
+    /// The existing configuration is saved to `backup` before anything is written,
+    /// failing if the file already exists so earlier backups are never overwritten.
+    /// Blocks are then written one at a time and read back to verify.
+    /// Note that this memory is one-time-programmable, bits may only be cleared.
+    pub fn set_prom_config<P: AsRef<Path>>(&self, config: &PromConfig, backup: P) -> Result<(), Error> {
+        let mut inner = lock(&self.inner)?;
+
+        // Backup existing configuration
+        let current = inner.get_prom_config()?;
+        current.save_new(&backup)?;
+
+        info!("Saved PROM backup to: {}", backup.as_ref().display());
+
+        // Write and verify new configuration
+        inner.set_prom_config(&current, config)
+    }
+
+    /// Fetch a snapshot of the device state, including per-channel SPI configuration,
+    /// chip select enables, pin modes and levels, and the clock divider
+    pub fn snapshot(&self) -> Result<DeviceState, Error> {
+        lock(&self.inner)?.snapshot()
+    }
+
+    /// Fetch the read FIFO full threshold, the number of bytes buffered
+    /// in the device before data is handed to the host
+    pub fn full_threshold(&self) -> Result<u8, Error> {
+        lock(&self.inner)?.get_full_threshold()
+    }
+
+    /// Set the read FIFO full threshold (1 to 255).
+    /// Lower values reduce latency, higher values improve throughput on long reads.
+    pub fn set_full_threshold(&self, threshold: u8) -> Result<(), Error> {
+        lock(&self.inner)?.set_full_threshold(threshold)
+    }
+
+    /// Fetch the safety margin added to expected SPI transfer times
+    pub fn pacing_margin(&self) -> Result<Duration, Error> {
+        Ok(lock(&self.inner)?.pacer.margin())
+    }
+
+    /// Set the safety margin added to expected SPI transfer times
+    pub fn set_pacing_margin(&self, margin: Duration) -> Result<(), Error> {
+        lock(&self.inner)?.pacer.set_margin(margin);
+        Ok(())
+    }
+
+    /// Replace the clock / sleep strategy used to pace SPI transfers
+    pub fn set_pacing_clock<C: Clock + 'static>(&self, clock: C) -> Result<(), Error> {
+        lock(&self.inner)?.pacer.set_clock(clock);
+        Ok(())
+    }
+
+    /// Create an SPI connector for the specified channel.
+    ///
+    /// Each connector owns its channel configuration, which is applied whenever
+    /// a transaction is issued on a different channel to the previous transaction.
+    ///
+    /// Where hardware chip select is enabled the matching GPIO pin is reserved
+    /// for the lifetime of the connector.
     pub fn spi(&self, channel: u8, config: SpiConfig) -> Result<Spi, Error> {
-        let mut inner = self.inner.lock().unwrap();
+        let cs = Spi::reserve_cs(&self.inner, channel, &config)?;
 
         // Configure SPI
-        inner.spi_configure(channel, config)?;
+        lock(&self.inner)?.spi_configure(channel, config.clone())?;
 
-        Ok(Spi{inner: self.inner.clone(), _channel: channel})
+        Ok(Spi{inner: self.inner.clone(), channel, config, cs})
     }
 
-    /// Create a GPIO OutputPin
-    pub fn gpio_out(&self, index: u8, mode: GpioMode, level: GpioLevel) -> Result<OutputPin, Error> {
-        let mut inner = self.inner.lock().unwrap();
-
-        if inner.gpio_allocated[index as usize] {
-            return Err(Error::GpioInUse)
+    /// Create an SPI device for the specified channel, using the hardware chip select
+    /// (GPIO pin matching the channel) held asserted across each transaction.
+    ///
+    /// Chip select is enabled if `config.cs_mode` is `CsMode::Disabled`.
+    pub fn spi_device(&self, channel: u8, mut config: SpiConfig) -> Result<SpiDevice, Error> {
+        if config.cs_mode == CsMode::Disabled {
+            config.cs_mode = CsMode::Enabled;
         }
 
-        inner.set_gpio_mode_level(index, mode, level)?;
-        inner.gpio_allocated[index as usize] = true;
+        Ok(SpiDevice{spi: self.spi(channel, config)?})
+    }
+
+    /// Create a CLKOUT clock generator on GPIO5.
+    ///
+    /// GPIO5 must be configured for CLKOUT in the pin configuration (see `set_pin_config`).
+    pub fn clock_out(&self) -> Result<ClockOut, Error> {
+        let handle = GpioHandle::claim_for(&self.inner, GpioPin::Gpio5, |f| f == PinFunction::ClockOut)?;
 
-        Ok(OutputPin{index, mode, inner: self.inner.clone()})
+        Ok(ClockOut{handle})
+    }
+
+    /// Create an event counter on GPIO4, counting events using the specified mode
+    pub fn event_counter(&self, mode: EventCounterMode) -> Result<EventCounter, Error> {
+        let handle = GpioHandle::claim_for(&self.inner, GpioPin::Gpio4, |f| f.allows_gpio() || matches!(f, PinFunction::EventCounter(_)))?;
+
+        lock(&self.inner)?.set_event_counter(mode, 0)?;
+
+        Ok(EventCounter{mode, handle})
+    }
+
+    /// Create a GPIO OutputPin
+    pub fn gpio_out(&self, pin: GpioPin, mode: GpioMode, level: GpioLevel) -> Result<OutputPin, Error> {
+        let handle = GpioHandle::claim(&self.inner, pin)?;
+        handle.set_mode_level(mode, level)?;
+
+        Ok(OutputPin{handle, mode, readback: false})
     }
 
     /// Create a GPIO InputPin
-    pub fn gpio_in(&self, index: u8) -> Result<InputPin, Error> {
-        let mut inner = self.inner.lock().unwrap();
+    pub fn gpio_in(&self, pin: GpioPin) -> Result<InputPin, Error> {
+        let handle = GpioHandle::claim(&self.inner, pin)?;
+        handle.set_mode_level(GpioMode::Input, GpioLevel::Low)?;
 
-        if inner.gpio_allocated[index as usize] {
-            return Err(Error::GpioInUse)
-        }
+        Ok(InputPin{handle})
+    }
 
-        inner.set_gpio_mode_level(index, GpioMode::Input, GpioLevel::Low)?;
-        inner.gpio_allocated[index as usize] = true;
+    /// Create a GpioPort over a set of GPIO pins, configured with the provided mode
+    /// and initial levels, for reading and writing the pins in a single request
+    pub fn gpio_port(&self, pins: &[GpioPin], mode: GpioMode, levels: GpioLevels) -> Result<GpioPort, Error> {
+        let mask = pins.iter().fold(GpioLevels::empty(), |m, p| m | GpioLevels::from(*p));
 
-        Ok(InputPin{index, inner: self.inner.clone()})
+        // Claim all pins before configuring any, claimed pins are released on failure
+        let handles = GpioPin::all().iter()
+            .filter(|p| mask.contains((**p).into()))
+            .map(|p| GpioHandle::claim(&self.inner, *p))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for h in &handles {
+            h.set_mode_level(mode, levels.level(h.pin.index())?)?;
+        }
+
+        Ok(GpioPort{mask, mode, _handles: handles, inner: self.inner.clone()})
     }
 
 }
@@ -147,151 +484,837 @@ impl Cp2130 {
 /// Underlying device functions
 impl  Device for Cp2130 {
     fn spi_read(&self, buff: &mut [u8]) -> Result<usize, Error> {
-        let mut inner = self.inner.lock().unwrap();
+        let mut inner = lock(&self.inner)?;
         inner.spi_read(buff)
     }
 
     fn spi_write(&self, buff: &[u8]) -> Result<(), Error> {
-        let mut inner = self.inner.lock().unwrap();
+        let mut inner = lock(&self.inner)?;
         inner.spi_write(buff)
     }
 
     fn spi_write_read(&self, buff_out: &[u8], buff_in: &mut [u8]) -> Result<usize, Error> {
-        let mut inner = self.inner.lock().unwrap();
+        let mut inner = lock(&self.inner)?;
         inner.spi_write_read(buff_out, buff_in)
     }
 
     fn version(&self) -> Result<u16, Error>  {
-        let mut inner = self.inner.lock().unwrap();
+        let mut inner = lock(&self.inner)?;
         inner.version()
     }
 
     fn set_gpio_mode_level(&self, pin: u8, mode: GpioMode, level: GpioLevel) -> Result<(), Error> {
-        let mut inner = self.inner.lock().unwrap();
+        let mut inner = lock(&self.inner)?;
         inner.set_gpio_mode_level(pin, mode, level)
     }
 
     fn get_gpio_values(&self) -> Result<GpioLevels, Error> {
-        let mut inner = self.inner.lock().unwrap();
+        let mut inner = lock(&self.inner)?;
         inner.get_gpio_values()
     }
 
     fn get_gpio_level(&self, pin: u8) -> Result<bool, Error> {
-        let mut inner = self.inner.lock().unwrap();
+        let mut inner = lock(&self.inner)?;
         inner.get_gpio_level(pin)
     }
+
+    fn set_gpio_values(&self, levels: GpioLevels, mask: GpioLevels) -> Result<(), Error> {
+        let mut inner = lock(&self.inner)?;
+        inner.set_gpio_values(levels, mask)
+    }
 }
 
 /// Spi object implements embedded-hal SPI traits for the CP2130
 pub struct Spi {
-    // TODO: use channel configuration
-    _channel: u8,
+    channel: u8,
+    config: SpiConfig,
     inner: Arc<Mutex<Inner>>,
+    cs: Option<GpioHandle>,
 }
 
+impl Spi {
+    /// Fetch the SPI channel for this connector
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    /// Fetch the SPI configuration for this connector
+    pub fn config(&self) -> &SpiConfig {
+        &self.config
+    }
+
+    /// Update the SPI configuration for this connector, this is applied on the next transaction.
+    ///
+    /// Enabling chip select reserves the channel's GPIO pin, disabling it releases the pin.
+    pub fn set_config(&mut self, config: SpiConfig) -> Result<(), Error> {
+        // Reserve any newly required pin first, so the connector is unchanged on failure
+        let cs = match self.cs.is_some() {
+            true => None,
+            false => Self::reserve_cs(&self.inner, self.channel, &config)?,
+        };
+
+        // Only then release the existing pin if it is no longer required
+        if config.cs_mode == CsMode::Disabled {
+            self.release_cs()?;
+        }
+
+        if cs.is_some() {
+            self.cs = cs;
+        }
 
-impl embedded_hal::spi::blocking::Transfer<u8> for Spi {
+        self.config = config;
 
-    fn transfer<'w>(&mut self, buff: &'w mut [u8], out: &'w [u8]) -> Result<(), Self::Error> {
-        let _n = self.inner.lock().unwrap().spi_write_read(&out, buff)?;
         Ok(())
     }
-}
 
-impl embedded_hal::spi::blocking::TransferInplace<u8> for Spi {
+    /// Reserve the chip select pin for a channel, if hardware chip select is enabled
+    fn reserve_cs(inner: &Arc<Mutex<Inner>>, channel: u8, config: &SpiConfig) -> Result<Option<GpioHandle>, Error> {
+        if config.cs_mode == CsMode::Disabled {
+            return Ok(None)
+        }
+
+        // Channel chip selects map directly to GPIO pins
+        let pin = GpioPin::try_from(channel).map_err(|_| Error::InvalidChannel(channel))?;
+
+        GpioHandle::claim(inner, pin).map(Some)
+    }
+
+    /// Disable hardware chip select and release the chip select pin
+    fn release_cs(&mut self) -> Result<(), Error> {
+        if self.cs.is_some() {
+            lock(&self.inner)?.spi_release(self.channel)?;
+            self.cs = None;
+        }
 
-    fn transfer_inplace<'w>(&mut self, buff: &'w mut [u8]) -> Result<(), Self::Error> {
-        let out = buff.to_vec();
-        let _n = self.inner.lock().unwrap().spi_write_read(&out, buff)?;
         Ok(())
     }
-}
 
+    /// Lock the underlying device and select this connector's channel configuration
+    fn select(&self) -> Result<std::sync::MutexGuard<'_, Inner>, Error> {
+        let mut inner = lock(&self.inner)?;
+        inner.spi_select(self.channel, &self.config)?;
+        Ok(inner)
+    }
+
+    /// Read from the SPI device, writing zeros
+    pub fn read(&mut self, buff: &mut [u8]) -> Result<(), Error> {
+        self.select()?.spi_write_read(&[], buff)?;
+        Ok(())
+    }
 
-impl embedded_hal::spi::blocking::Write<u8> for Spi {
+    /// Write to the SPI device, discarding read data
+    pub fn write(&mut self, words: &[u8]) -> Result<(), Error> {
+        self.select()?.spi_write(words)
+    }
 
-    fn write(&mut self, words: &[u8] ) -> Result<(), Self::Error> {
-        let _n = self.inner.lock().unwrap().spi_write(words)?;
+    /// Write to and read from the SPI device
+    pub fn transfer(&mut self, buff: &mut [u8], out: &[u8]) -> Result<(), Error> {
+        self.select()?.spi_write_read(out, buff)?;
         Ok(())
     }
-}
 
-impl embedded_hal::spi::blocking::Read<u8> for Spi {
+    /// Write to and read from the SPI device, replacing the buffer contents with read data
+    pub fn transfer_in_place(&mut self, buff: &mut [u8]) -> Result<(), Error> {
+        let out = buff.to_vec();
+        self.select()?.spi_write_read(&out, buff)?;
+        Ok(())
+    }
 
-    fn read(&mut self, buff: &mut [u8] ) -> Result<(), Self::Error> {
-        let out = vec![0u8; buff.len()];
-        let _n = self.inner.lock().unwrap().spi_write_read(&out, buff)?;
+    /// Wait for any buffered writes to be clocked out
+    pub fn flush(&mut self) -> Result<(), Error> {
+        lock(&self.inner)?.pacer.wait();
         Ok(())
     }
-}
 
-use embedded_hal::spi::blocking::{Operation, Read as _, Write as _, Transfer as _, TransferInplace};
+    /// Read from the SPI device using ready-to-read (RTR) flow control,
+    /// data is only clocked while the slave asserts RTR on GPIO3.
+    ///
+    /// GPIO3 must be configured for RTR in the pin configuration.
+    /// If the read does not complete within `timeout` it is aborted and `Error::RtrTimeout` is returned.
+    pub fn read_rtr(&mut self, buff: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        let mut inner = self.select()?;
+
+        let function = inner.pin_function(RTR_PIN)?;
+        if !matches!(function, PinFunction::Rtr | PinFunction::NotRtr) {
+            return Err(Error::GpioFunction(RTR_PIN, function))
+        }
 
-/// Default impl for transactional SPI
-impl embedded_hal::spi::blocking::Transactional<u8> for Spi {
+        inner.spi_read_rtr(buff, timeout)
+    }
 
-    fn exec<'a>(&mut self, operations: &mut [Operation<'a, u8>]) -> Result<(), Self::Error> {
-        for o in operations {
-            match o {
-                Operation::Write(w) => self.write(w)?,
-                Operation::Transfer(r, w) => self.transfer(r, w)?,
-                Operation::TransferInplace(b) => self.transfer_inplace(b)?,
-                Operation::Read(r) => self.read(r)?,
-            }
+    /// Fetch the RTR read state
+    pub fn rtr_state(&self) -> Result<RtrState, Error> {
+        lock(&self.inner)?.get_rtr_state()
+    }
+
+    /// Abort any active RTR read
+    pub fn abort_rtr(&mut self) -> Result<(), Error> {
+        lock(&self.inner)?.abort_rtr()
+    }
+}
+
+
+impl Drop for Spi {
+    fn drop(&mut self) {
+        if let Err(e) = self.release_cs() {
+            warn!("Failed to release SPI channel {} chip select: {:?}", self.channel, e);
         }
+    }
+}
 
-        Ok(())
+#[cfg(feature = "eh1")]
+impl embedded_hal::spi::SpiBus<u8> for Spi {
+
+    fn read(&mut self, buff: &mut [u8]) -> Result<(), Self::Error> {
+        Spi::read(self, buff)
+    }
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        Spi::write(self, words)
+    }
+
+    fn transfer(&mut self, buff: &mut [u8], out: &[u8]) -> Result<(), Self::Error> {
+        Spi::transfer(self, buff, out)
+    }
+
+    fn transfer_in_place(&mut self, buff: &mut [u8]) -> Result<(), Self::Error> {
+        Spi::transfer_in_place(self, buff)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Spi::flush(self)
     }
 }
 
+#[cfg(feature = "eh1")]
 impl embedded_hal::spi::ErrorType for Spi {
     type Error = Error;
 }
 
+#[cfg(feature = "eh1")]
 impl embedded_hal::spi::Error for Error {
     fn kind(&self) -> embedded_hal::spi::ErrorKind {
-        embedded_hal::spi::ErrorKind::Other
+        match Error::kind(self) {
+            ErrorKind::ShortTransfer => embedded_hal::spi::ErrorKind::Overrun,
+            ErrorKind::InUse => embedded_hal::spi::ErrorKind::ChipSelectFault,
+            _ => embedded_hal::spi::ErrorKind::Other,
+        }
     }
 }
-/// InputPin object implements embedded-hal InputPin traits for the CP2130
-pub struct InputPin {
-    index: u8,
+
+#[cfg(feature = "eh1")]
+impl embedded_hal::digital::Error for Error {
+    fn kind(&self) -> embedded_hal::digital::ErrorKind {
+        embedded_hal::digital::ErrorKind::Other
+    }
+}
+
+/// Claimed GPIO pin, shared by pin types and released on drop
+struct GpioHandle {
+    pin: GpioPin,
     inner: Arc<Mutex<Inner>>,
 }
 
-impl  embedded_hal::digital::blocking::InputPin for InputPin {
-    fn is_high(&self) -> Result<bool, Self::Error> {
-        self.inner.lock().unwrap().get_gpio_level(self.index)
+impl GpioHandle {
+    /// Claim a GPIO pin, failing if it is in use or assigned to an alternate function
+    fn claim(inner: &Arc<Mutex<Inner>>, pin: GpioPin) -> Result<Self, Error> {
+        Self::claim_for(inner, pin, |f| f.allows_gpio())
+    }
+
+    /// Claim a GPIO pin, failing if it is in use or its configured function is not allowed
+    fn claim_for<F: Fn(PinFunction) -> bool>(inner: &Arc<Mutex<Inner>>, pin: GpioPin, allowed: F) -> Result<Self, Error> {
+        let mut i = lock(inner)?;
+        let index = pin.index();
+
+        if i.gpio_allocated[index as usize] {
+            return Err(Error::GpioInUse(index))
+        }
+
+        let function = i.pin_function(index)?;
+        if !allowed(function) {
+            return Err(Error::GpioFunction(index, function))
+        }
+
+        i.gpio_allocated[index as usize] = true;
+
+        Ok(GpioHandle{pin, inner: inner.clone()})
+    }
+
+    fn set_mode_level(&self, mode: GpioMode, level: GpioLevel) -> Result<(), Error> {
+        lock(&self.inner)?.set_gpio_mode_level(self.pin.index(), mode, level)
+    }
+
+    fn level(&self) -> Result<bool, Error> {
+        lock(&self.inner)?.get_gpio_level(self.pin.index())
     }
 
-    fn is_low(&self) -> Result<bool, Self::Error> {
+    fn cached_level(&self) -> Result<GpioLevel, Error> {
+        lock(&self.inner)?.get_gpio_level_cached(self.pin.index())
+    }
+
+    fn into_input(self) -> Result<InputPin, Error> {
+        self.set_mode_level(GpioMode::Input, GpioLevel::Low)?;
+        Ok(InputPin{handle: self})
+    }
+
+    fn into_output(self, mode: GpioMode, level: GpioLevel) -> Result<OutputPin, Error> {
+        self.set_mode_level(mode, level)?;
+        Ok(OutputPin{handle: self, mode, readback: false})
+    }
+}
+
+impl Drop for GpioHandle {
+    fn drop(&mut self) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.gpio_allocated[self.pin as usize] = false;
+        }
+    }
+}
+
+/// SpiDevice object implements the embedded-hal SpiDevice trait for the CP2130,
+/// keeping the hardware chip select asserted across all operations in a transaction.
+///
+/// Transactions containing `Operation::DelayNs` can not be issued as a single command,
+/// so the channel chip select pin is instead driven as a GPIO (using the configured
+/// `cs_pin_mode`) and each operation is issued separately. The post-assert and
+/// pre-deassert delays are then applied in software, so timing is only as precise as
+/// the host scheduler allows. Hardware chip select is re-enabled by the next transaction
+/// without delays.
+pub struct SpiDevice {
+    spi: Spi,
+}
+
+impl SpiDevice {
+    /// Fetch the SPI channel for this device
+    pub fn channel(&self) -> u8 {
+        self.spi.channel
+    }
+
+    /// Fetch the SPI configuration for this device
+    pub fn config(&self) -> &SpiConfig {
+        &self.spi.config
+    }
+
+    /// Fetch the underlying SPI connector
+    pub fn into_inner(self) -> Spi {
+        self.spi
+    }
+
+    /// Execute a sequence of operations with chip select asserted throughout
+    pub fn transaction(&mut self, operations: &mut [embedded_hal::spi::Operation<'_, u8>]) -> Result<(), Error> {
+        lock(&self.spi.inner)?.spi_transaction(self.spi.channel, &self.spi.config, operations)
+    }
+}
+
+#[cfg(feature = "eh1")]
+impl embedded_hal::spi::ErrorType for SpiDevice {
+    type Error = Error;
+}
+
+#[cfg(feature = "eh1")]
+impl embedded_hal::spi::SpiDevice<u8> for SpiDevice {
+    fn transaction(&mut self, operations: &mut [embedded_hal::spi::Operation<'_, u8>]) -> Result<(), Self::Error> {
+        SpiDevice::transaction(self, operations)
+    }
+}
+
+/// InputPin object implements embedded-hal InputPin traits for the CP2130,
+/// releasing the pin on drop
+pub struct InputPin {
+    handle: GpioHandle,
+}
+
+impl InputPin {
+    /// Fetch the GPIO pin
+    pub fn pin(&self) -> GpioPin {
+        self.handle.pin
+    }
+
+    /// Read the current pin level
+    pub fn level(&self) -> Result<GpioLevel, Error> {
+        match self.handle.level()? {
+            true => Ok(GpioLevel::High),
+            false => Ok(GpioLevel::Low),
+        }
+    }
+
+    /// Reconfigure the pin as an output with the provided mode and initial level
+    pub fn into_output(self, mode: GpioMode, level: GpioLevel) -> Result<OutputPin, Error> {
+        self.handle.into_output(mode, level)
+    }
+
+    /// Reconfigure the pin as an open-drain output, initially released (high)
+    pub fn into_open_drain(self) -> Result<OutputPin, Error> {
+        self.handle.into_output(GpioMode::OpenDrain, GpioLevel::High)
+    }
+}
+
+#[cfg(feature = "eh1")]
+impl  embedded_hal::digital::InputPin for InputPin {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        self.handle.level()
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
         let v = self.is_high()?;
         Ok(!v)
     }
 }
 
+#[cfg(feature = "eh1")]
 impl embedded_hal::digital::ErrorType for InputPin {
     type Error = Error;
 }
 
-/// OutputPin object implements embedded-hal OutputPin traits for the CP2130
+/// OutputPin object implements embedded-hal OutputPin traits for the CP2130,
+/// releasing the pin on drop
 pub struct OutputPin {
-    index: u8,
+    handle: GpioHandle,
     mode: GpioMode,
-    inner: Arc<Mutex<Inner>>,
+    readback: bool,
+}
+
+impl OutputPin {
+    /// Fetch the GPIO pin
+    pub fn pin(&self) -> GpioPin {
+        self.handle.pin
+    }
+
+    /// Fetch the output mode
+    pub fn mode(&self) -> GpioMode {
+        self.mode
+    }
+
+    /// Read back the actual pin level rather than the last written level when checking
+    /// the output state, for open-drain pins where another device may hold the line low
+    pub fn set_readback(&mut self, readback: bool) {
+        self.readback = readback;
+    }
+
+    /// Set the output level
+    pub fn set_level(&mut self, level: GpioLevel) -> Result<(), Error> {
+        self.handle.set_mode_level(self.mode, level)
+    }
+
+    /// Fetch the output level, this is the last written level unless readback is enabled
+    pub fn level(&self) -> Result<GpioLevel, Error> {
+        match self.readback {
+            true => match self.handle.level()? {
+                true => Ok(GpioLevel::High),
+                false => Ok(GpioLevel::Low),
+            },
+            false => self.handle.cached_level(),
+        }
+    }
+
+    /// Toggle the output level, based on the last written level
+    pub fn toggle(&mut self) -> Result<(), Error> {
+        match self.handle.cached_level()? {
+            GpioLevel::High => self.set_level(GpioLevel::Low),
+            GpioLevel::Low => self.set_level(GpioLevel::High),
+        }
+    }
+
+    /// Reconfigure the pin as an input
+    pub fn into_input(self) -> Result<InputPin, Error> {
+        self.handle.into_input()
+    }
+
+    /// Reconfigure the pin with a new output mode and level
+    pub fn into_output(self, mode: GpioMode, level: GpioLevel) -> Result<OutputPin, Error> {
+        self.handle.into_output(mode, level)
+    }
+
+    /// Reconfigure the pin as an open-drain output, initially released (high)
+    pub fn into_open_drain(self) -> Result<OutputPin, Error> {
+        self.handle.into_output(GpioMode::OpenDrain, GpioLevel::High)
+    }
 }
 
-impl  embedded_hal::digital::blocking::OutputPin for OutputPin {
+#[cfg(feature = "eh1")]
+impl  embedded_hal::digital::OutputPin for OutputPin {
     fn set_high(&mut self) -> Result<(), Self::Error> {
-        self.inner.lock().unwrap().set_gpio_mode_level(self.index, self.mode, GpioLevel::High)
+        self.set_level(GpioLevel::High)
     }
 
     fn set_low(&mut self) -> Result<(), Self::Error> {
-        self.inner.lock().unwrap().set_gpio_mode_level(self.index, self.mode, GpioLevel::Low)
+        self.set_level(GpioLevel::Low)
+    }
+}
+
+#[cfg(feature = "eh1")]
+impl  embedded_hal::digital::StatefulOutputPin for OutputPin {
+    fn is_set_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.level()? == GpioLevel::High)
+    }
+
+    fn is_set_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.level()? == GpioLevel::Low)
+    }
+
+    fn toggle(&mut self) -> Result<(), Self::Error> {
+        OutputPin::toggle(self)
     }
 }
 
 
+#[cfg(feature = "eh1")]
 impl embedded_hal::digital::ErrorType for OutputPin {
     type Error = Error;
-}
\ No newline at end of file
+}
+
+/// GpioPort object reads and writes a set of GPIO pins in a single request,
+/// releasing the pins on drop
+pub struct GpioPort {
+    mask: GpioLevels,
+    mode: GpioMode,
+    _handles: Vec<GpioHandle>,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl GpioPort {
+    /// Fetch the pins in this port
+    pub fn pins(&self) -> GpioLevels {
+        self.mask
+    }
+
+    /// Fetch the mode for pins in this port
+    pub fn mode(&self) -> GpioMode {
+        self.mode
+    }
+
+    /// Read the levels of all pins in this port
+    pub fn read(&self) -> Result<GpioLevels, Error> {
+        let levels = lock(&self.inner)?.get_gpio_values()?;
+        Ok(levels & self.mask)
+    }
+
+    /// Write the levels of all pins in this port
+    pub fn write(&mut self, levels: GpioLevels) -> Result<(), Error> {
+        self.write_masked(levels, self.mask)
+    }
+
+    /// Write the levels of the pins in this port selected by `mask`,
+    /// leaving other pins unchanged
+    pub fn write_masked(&mut self, levels: GpioLevels, mask: GpioLevels) -> Result<(), Error> {
+        // Reject pins outside of the port
+        if let Some(p) = (mask - self.mask).pins().first() {
+            return Err(Error::InvalidPin(*p))
+        }
+
+        lock(&self.inner)?.set_gpio_values(levels & mask, mask)
+    }
+
+    /// Drive the pins selected by `mask` high
+    pub fn set_high(&mut self, mask: GpioLevels) -> Result<(), Error> {
+        self.write_masked(GpioLevels::all(), mask)
+    }
+
+    /// Drive the pins selected by `mask` low
+    pub fn set_low(&mut self, mask: GpioLevels) -> Result<(), Error> {
+        self.write_masked(GpioLevels::empty(), mask)
+    }
+}
+
+/// ClockOut object controls the CLKOUT clock generator on GPIO5, releasing the pin on drop
+pub struct ClockOut {
+    handle: GpioHandle,
+}
+
+impl ClockOut {
+    /// Set the output to the closest available frequency to the target (in Hz),
+    /// returning the selected divider, actual frequency and frequency error
+    pub fn set_frequency(&mut self, target: u32) -> Result<ClockFrequency, Error> {
+        let f = ClockFrequency::for_target(target)?;
+        lock(&self.handle.inner)?.set_clock_divider(&f)?;
+        Ok(f)
+    }
+
+    /// Set the output clock divider (1 to 256)
+    pub fn set_divider(&mut self, divider: u16) -> Result<ClockFrequency, Error> {
+        let f = ClockFrequency::from_divider(divider)?;
+        lock(&self.handle.inner)?.set_clock_divider(&f)?;
+        Ok(f)
+    }
+
+    /// Fetch the current clock divider and output frequency
+    pub fn frequency(&self) -> Result<ClockFrequency, Error> {
+        lock(&self.handle.inner)?.get_clock_divider()
+    }
+}
+
+/// EventCounter object counts events on GPIO4, releasing the pin on drop
+pub struct EventCounter {
+    mode: EventCounterMode,
+    handle: GpioHandle,
+}
+
+/// Event rate measured over a sampling window
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct EventRate {
+    /// Number of events counted
+    pub count: u16,
+    /// Duration of the sampling window
+    pub window: Duration,
+}
+
+impl EventRate {
+    /// Event frequency in Hz
+    pub fn frequency(&self) -> f64 {
+        self.count as f64 / self.window.as_secs_f64()
+    }
+
+    /// Rotational speed in RPM, for a given number of events per revolution
+    pub fn rpm(&self, events_per_rev: u32) -> f64 {
+        self.frequency() * 60.0 / events_per_rev as f64
+    }
+}
+
+impl EventCounter {
+    /// Fetch the event counter mode
+    pub fn mode(&self) -> EventCounterMode {
+        self.mode
+    }
+
+    /// Set the event counter mode, this resets the count
+    pub fn set_mode(&mut self, mode: EventCounterMode) -> Result<(), Error> {
+        lock(&self.handle.inner)?.set_event_counter(mode, 0)?;
+        self.mode = mode;
+        Ok(())
+    }
+
+    /// Fetch the current count, returning `Error::CounterOverflow` if the counter has overflowed
+    pub fn count(&self) -> Result<u16, Error> {
+        let c = lock(&self.handle.inner)?.get_event_counter()?;
+        if c.overflow {
+            return Err(Error::CounterOverflow)
+        }
+        Ok(c.count)
+    }
+
+    /// Fetch the raw event counter state, including the overflow flag
+    pub fn state(&self) -> Result<EventCount, Error> {
+        lock(&self.handle.inner)?.get_event_counter()
+    }
+
+    /// Reset the count and clear the overflow flag
+    pub fn reset(&mut self) -> Result<(), Error> {
+        lock(&self.handle.inner)?.set_event_counter(self.mode, 0)
+    }
+
+    /// Count events over the provided sampling window to estimate the event rate.
+    /// The window must be short enough that the 16-bit counter does not overflow.
+    pub fn measure(&mut self, window: Duration) -> Result<EventRate, Error> {
+        self.reset()?;
+        let start = Instant::now();
+
+        std::thread::sleep(window);
+
+        let count = self.count()?;
+        let window = start.elapsed();
+
+        Ok(EventRate{count, window})
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::transport::fake::FakeTransport;
+    use crate::manager::{Manager, Filter};
+    use crate::otp::PIN_CONFIG_SIZE;
+
+    /// Create a device over a fake transport
+    pub(crate) fn fake() -> (Cp2130, FakeTransport) {
+        let (inner, t) = crate::device::tests::fake();
+
+        (Cp2130{inner: Arc::new(Mutex::new(inner)), info: Info::default()}, t)
+    }
+
+    #[test]
+    fn error_kind() {
+        // USB errors are categorised by cause
+        let e = Error::usb(Op::Bulk, rusb::Error::Timeout);
+        assert_eq!(e.kind(), ErrorKind::Timeout);
+        assert_eq!(Error::usb(Op::Bulk, rusb::Error::NoDevice).kind(), ErrorKind::Disconnected);
+        assert_eq!(Error::usb(Op::Bulk, rusb::Error::Pipe).kind(), ErrorKind::Stall);
+        assert_eq!(Error::usb(Op::Bulk, rusb::Error::Access).kind(), ErrorKind::Permission);
+        assert_eq!(Error::usb(Op::Bulk, rusb::Error::Busy).kind(), ErrorKind::Other);
+
+        // Operation context is replaced for transport errors only
+        let e = e.with_op(Op::Device);
+        assert_eq!(e.op(), Some(Op::Device));
+        assert_eq!(Error::InvalidPin(12).with_op(Op::Device).op(), None);
+
+        assert_eq!(Error::InvalidPin(12).kind(), ErrorKind::InvalidArgument);
+        assert_eq!(Error::GpioInUse(3).kind(), ErrorKind::InUse);
+
+        // Short transfers map to the embedded-hal overrun kind
+        let e = Error::ShortTransfer{op: Op::Bulk, actual: 2, expected: 4};
+        #[cfg(feature = "eh1")]
+        assert_eq!(embedded_hal::spi::Error::kind(&e), embedded_hal::spi::ErrorKind::Overrun);
+        assert_eq!(e.to_string(), "Short transfer during bulk transfer (2 of 4 bytes)");
+    }
+
+    /// Out of range arguments should return errors rather than panicking
+    #[test]
+    fn no_panic_on_invalid_arguments() {
+        let (cp, t) = fake();
+
+        for pin in [11u8, 12, 127, 255].iter().copied() {
+            assert!(matches!(GpioLevels::pin(pin), Err(Error::InvalidPin(p)) if p == pin));
+            assert!(GpioLevels::from_pins(&[0, pin]).is_err());
+            assert!(GpioLevels::empty().level(pin).is_err());
+            assert!(GpioLevels::empty().set_level(pin, GpioLevel::High).is_err());
+
+            let mut c = PinConfig::default();
+            assert!(c.function(pin).is_err());
+            assert!(c.set_function(pin, PinFunction::PushPull).is_err());
+            assert!(!PinFunction::Input.supported(pin));
+
+            // Device entry points taking raw pin and channel indices
+            assert!(matches!(cp.set_gpio_mode_level(pin, GpioMode::PushPull, GpioLevel::High), Err(Error::InvalidPin(p)) if p == pin));
+            assert!(cp.get_gpio_level(pin).is_err());
+            assert!(matches!(cp.spi(pin, SpiConfig::default()), Err(Error::InvalidChannel(c)) if c == pin));
+            assert!(cp.spi_device(pin, SpiConfig::default()).is_err());
+        }
+
+        assert!(matches!(cp.set_usb_string(UsbString::Serial, &"x".repeat(31)), Err(Error::StringLength(30))));
+
+        let mut config = PinConfig::default();
+        config.set_function(5, PinFunction::ClockOut).unwrap();
+        t.set_response(Commands::GetPinConfig as u8, &config.encode());
+        cp.reset().unwrap();
+
+        let mut clock = cp.clock_out().unwrap();
+        for d in [0u16, 257, u16::MAX].iter() {
+            assert!(matches!(ClockFrequency::from_divider(*d), Err(Error::InvalidDivider(_))));
+            assert!(matches!(clock.set_divider(*d), Err(Error::InvalidDivider(_))));
+        }
+        for f in [0u32, 1, u32::MAX].iter() {
+            assert!(matches!(ClockFrequency::for_target(*f), Err(Error::InvalidFrequency(_))));
+            assert!(matches!(clock.set_frequency(*f), Err(Error::InvalidFrequency(_))));
+        }
+
+        assert!(PromConfig::from_bytes(&[]).is_err());
+        assert!(PromConfig::default().block(usize::MAX).is_none());
+        assert!(SpiClock::try_from(usize::MAX).is_err());
+
+        // Extreme durations saturate rather than overflowing
+        let _ = SpiClock::Clock12Mhz.transfer_time(u64::MAX);
+        let p = TransferPolicy{backoff: Duration::MAX, ..Default::default()};
+        assert_eq!(p.backoff(u32::MAX), Duration::MAX);
+
+        let mut pacer = Pacer::default();
+        pacer.set_margin(Duration::MAX);
+        pacer.busy(Duration::MAX);
+        assert!(pacer.remaining() > Duration::from_secs(0));
+    }
+
+    /// Malformed device responses should return errors rather than panicking
+    #[test]
+    fn no_panic_on_malformed_responses() {
+        let (cp, t) = fake();
+
+        let requests = [
+            Commands::GetClockDivider, Commands::GetEventCounter, Commands::GetFullThreshold,
+            Commands::GetGpioChipSelect, Commands::GetGpioModeAndLevel, Commands::GetGpioValues,
+            Commands::GetLockByte, Commands::GetManufacturingString1, Commands::GetManufacturingString2,
+            Commands::GetPinConfig, Commands::GetProductString1, Commands::GetProductString2,
+            Commands::GetPromConfig, Commands::GetRtrState, Commands::GetSpiWord, Commands::GetSpiDelay,
+            Commands::GetReadOnlyVersion, Commands::GetSerialString, Commands::GetUsbConfig,
+        ];
+
+        for buff in [&[][..], &[0x00, 0x03], &[0x01, 0x03], &[0xFF, 0x03, 0x41], &[0xFF; 3], &[0xFF; 64]].iter() {
+            let _ = UsbString::Serial.decode(buff);
+            let _ = UsbString::Manufacturer.decode(buff);
+
+            assert!(buff.len() >= PIN_CONFIG_SIZE || PinConfig::decode(buff).is_err());
+            assert!(buff.len() >= 9 || UsbConfig::decode(buff).is_err());
+
+            // Device entry points decoding each response
+            for r in requests.iter() {
+                t.set_response(*r as u8, buff);
+            }
+
+            let _ = cp.reset();
+            let _ = cp.version();
+            let _ = cp.usb_config();
+            let _ = cp.usb_string(UsbString::Manufacturer);
+            let _ = cp.usb_string(UsbString::Product);
+            let _ = cp.usb_string(UsbString::Serial);
+            let _ = cp.lock_byte();
+            let _ = cp.pin_config();
+            let _ = cp.prom_config();
+            let _ = cp.snapshot();
+            let _ = cp.full_threshold();
+            let _ = cp.get_gpio_values();
+            let _ = cp.get_gpio_level(0);
+
+            if let Ok(spi) = cp.spi(0, SpiConfig::default()) {
+                let _ = spi.rtr_state();
+            }
+            if let Ok(counter) = cp.event_counter(EventCounterMode::RisingEdge) {
+                let _ = counter.state();
+            }
+        }
+    }
+
+    /// A poisoned device lock should return errors rather than panicking
+    #[test]
+    fn no_panic_on_poisoned_lock() {
+        let (cp, _t) = fake();
+        let pin = cp.gpio_in(GpioPin::Gpio0).unwrap();
+
+        let inner = cp.inner.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = inner.lock().unwrap();
+            panic!("poisoning device lock");
+        }).join();
+
+        assert!(matches!(cp.version(), Err(Error::Poisoned)));
+        assert!(matches!(cp.spi(0, SpiConfig::default()), Err(Error::Poisoned)));
+        assert!(matches!(pin.level(), Err(Error::Poisoned)));
+
+        // Handles are released without panicking
+        drop(pin);
+    }
+
+    #[test]
+    fn spi_chip_select_reservation() {
+        let (d, _t) = fake();
+        let enabled = SpiConfig{cs_mode: CsMode::Enabled, ..SpiConfig::default()};
+
+        // Hardware chip select reserves the channel's GPIO pin
+        let mut spi = d.spi(1, enabled.clone()).unwrap();
+        assert!(matches!(d.gpio_out(GpioPin::Gpio1, GpioMode::PushPull, GpioLevel::High), Err(Error::GpioInUse(1))));
+
+        // Disabling chip select releases the pin
+        spi.set_config(SpiConfig::default()).unwrap();
+        let pin = d.gpio_out(GpioPin::Gpio1, GpioMode::PushPull, GpioLevel::High).unwrap();
+
+        // Re-enabling fails while the pin is in use, leaving the connector unchanged
+        assert!(matches!(spi.set_config(enabled.clone()), Err(Error::GpioInUse(1))));
+        assert_eq!(spi.config().cs_mode, CsMode::Disabled);
+
+        drop(pin);
+        spi.set_config(enabled).unwrap();
+        assert!(d.gpio_in(GpioPin::Gpio1).is_err());
+
+        // Pins are released when the connector is dropped
+        drop(spi);
+        d.gpio_in(GpioPin::Gpio1).unwrap();
+
+        // SPI devices always reserve their chip select
+        let dev = d.spi_device(2, SpiConfig::default()).unwrap();
+        assert!(matches!(d.gpio_in(GpioPin::Gpio2), Err(Error::GpioInUse(2))));
+        drop(dev);
+        d.gpio_in(GpioPin::Gpio2).unwrap();
+    }
+
+    /// Connecting with an out of range index should fail without a device attached
+    #[test]
+    fn no_panic_on_invalid_device() {
+        assert!(Manager::device(Filter::default(), usize::MAX).is_err());
+    }
+}
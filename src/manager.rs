@@ -11,14 +11,12 @@ use std::num::ParseIntError;
 #[cfg(feature = "structopt")]
 use structopt::StructOpt;
 
-use crate::{Error};
+use crate::{Error, Op};
 use crate::device::{VID, PID};
 
 lazy_static!{
     // LibUSB context created automagically
-    static ref CONTEXT: UsbContext = {
-        UsbContext::new().unwrap()
-    };
+    static ref CONTEXT: Result<UsbContext, rusb::Error> = UsbContext::new();
 }
 
 /// Manager object maintains libusb context and provides
@@ -56,11 +54,13 @@ impl Manager {
         debug!("Fetching available USB devices");
 
         // Attempt to fetch device list
-        let devices = match CONTEXT.devices() {
+        let context = CONTEXT.as_ref().map_err(|e| Error::usb(Op::Device, *e))?;
+
+        let devices = match context.devices() {
             Ok(v) => v,
             Err(e) => {
                 error!("Fetching devices: {}", e);
-                return Err(Error::Usb(e))
+                return Err(Error::usb(Op::Device, e))
             }
         };
 
@@ -97,10 +97,10 @@ impl Manager {
         let mut matches = Self::devices_filtered(filter)?;
 
         // Check index is valid
-        if matches.len() < index || matches.len() == 0 {
+        if index >= matches.len() {
             error!("Device index ({}) exceeds number of discovered devices ({})",
                 index, matches.len());
-            return Err(Error::InvalidIndex)
+            return Err(Error::InvalidDevice(index))
         }
 
         // Return match
@@ -0,0 +1,1060 @@
+//! CP2130 Driver One-Time-Programmable (OTP) Configuration
+//!
+//!
+//! Copyright 2019 Ryan Kurte
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+use byteorder::{LE, ByteOrder};
+use bitflags::bitflags;
+
+use crate::{Error, Op};
+use crate::device::{Inner, Commands, OTP_MEMORY_KEY, GPIO_PINS, GpioLevels, EventCounterMode};
+
+/// Size of a single PROM configuration block
+pub const PROM_BLOCK_SIZE: usize = 64;
+
+/// Number of PROM configuration blocks
+pub const PROM_BLOCK_COUNT: usize = 8;
+
+/// Total size of the PROM configuration image
+pub const PROM_SIZE: usize = PROM_BLOCK_SIZE * PROM_BLOCK_COUNT;
+
+/// Raw image of the CP2130 PROM (OTP configuration) block,
+/// as read and written by the Get/Set PROM Config commands
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromConfig {
+    data: [u8; PROM_SIZE],
+}
+
+impl Default for PromConfig {
+    /// Unprogrammed OTP reads as all ones
+    fn default() -> Self {
+        Self{ data: [0xFF; PROM_SIZE] }
+    }
+}
+
+impl PromConfig {
+    /// Create a PROM image from raw bytes, these must be exactly `PROM_SIZE` long
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        if data.len() != PROM_SIZE {
+            return Err(Error::PromLength(data.len()))
+        }
+
+        let mut c = Self::default();
+        c.data.copy_from_slice(data);
+
+        Ok(c)
+    }
+
+    /// Fetch the raw bytes of the PROM image
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Fetch a single block of the PROM image
+    pub fn block(&self, index: usize) -> Option<&[u8]> {
+        self.data.chunks(PROM_BLOCK_SIZE).nth(index)
+    }
+
+    /// Fetch a mutable reference to a single block of the PROM image
+    pub fn block_mut(&mut self, index: usize) -> Option<&mut [u8]> {
+        self.data.chunks_mut(PROM_BLOCK_SIZE).nth(index)
+    }
+
+    /// Iterate over the blocks in the PROM image
+    pub fn blocks(&self) -> impl Iterator<Item=&[u8]> {
+        self.data.chunks(PROM_BLOCK_SIZE)
+    }
+
+    /// Check this image can be re-programmed to `config`, as OTP bits may only be cleared
+    pub fn check_writable(&self, config: &PromConfig) -> Result<(), Error> {
+        for (i, (c, n)) in self.blocks().zip(config.blocks()).enumerate() {
+            if c.iter().zip(n.iter()).any(|(c, n)| n & !c != 0) {
+                error!("PROM block {} requires setting cleared bits (current: {:02x?} new: {:02x?})", i, c, n);
+                return Err(Error::PromUnwritable(i as u8))
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Load a PROM image from a binary file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let data = fs::read(path)?;
+        Self::from_bytes(&data)
+    }
+
+    /// Save a PROM image to a binary file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        fs::write(path, &self.data[..])?;
+        Ok(())
+    }
+
+    /// Save a PROM image to a new binary file, failing if the file already exists
+    pub fn save_new<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let mut f = fs::OpenOptions::new().write(true).create_new(true).open(path)?;
+        f.write_all(&self.data[..])?;
+        Ok(())
+    }
+}
+
+bitflags!(
+    /// Lock byte flags, a set flag indicates the field is unlocked (still writable)
+    pub struct LockByte: u16 {
+        const VID                   = 1 << 0;
+        const PID                   = 1 << 1;
+        const MAX_POWER             = 1 << 2;
+        const POWER_MODE            = 1 << 3;
+        const RELEASE_VERSION       = 1 << 4;
+        const MANUFACTURER_STRING_1 = 1 << 5;
+        const MANUFACTURER_STRING_2 = 1 << 6;
+        const TRANSFER_PRIORITY     = 1 << 7;
+        const PRODUCT_STRING_1      = 1 << 8;
+        const PRODUCT_STRING_2      = 1 << 9;
+        const SERIAL_STRING         = 1 << 10;
+        const PIN_CONFIG            = 1 << 11;
+    }
+);
+
+/// Explicit confirmation required to permanently lock OTP fields.
+///
+/// Locking is irreversible, once a field is locked it can never be written again.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LockConfirmation {
+    fields: LockByte,
+}
+
+impl LockConfirmation {
+    /// Confirm that the specified fields should be permanently locked, this cannot be undone
+    pub fn irreversibly_lock(fields: LockByte) -> Self {
+        Self{ fields }
+    }
+
+    /// Fetch the fields to be locked
+    pub fn fields(&self) -> LockByte {
+        self.fields
+    }
+
+    /// Compute the raw lock byte value with the confirmed fields locked (cleared),
+    /// leaving all other bits (including reserved bits) unchanged
+    pub fn lock_value(&self, current: u16) -> u16 {
+        current & !self.fields.bits()
+    }
+}
+
+/// Size of a single programmable string block
+pub const STRING_BLOCK_SIZE: usize = 64;
+
+/// USB string descriptor type
+const STRING_DESCRIPTOR_TYPE: u8 = 0x03;
+
+/// Programmable USB strings
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum UsbString {
+    /// Manufacturer string, split across Manufacturing String 1 and 2
+    Manufacturer,
+    /// Product string, split across Product String 1 and 2
+    Product,
+    /// Serial number string
+    Serial,
+}
+
+impl UsbString {
+    /// Maximum string length in (UTF-16) characters
+    pub fn max_len(&self) -> usize {
+        match self {
+            UsbString::Manufacturer => 62,
+            UsbString::Product => 62,
+            UsbString::Serial => 30,
+        }
+    }
+
+    /// Get commands for each of the blocks making up the string
+    fn get_commands(&self) -> &'static [Commands] {
+        match self {
+            UsbString::Manufacturer => &[Commands::GetManufacturingString1, Commands::GetManufacturingString2],
+            UsbString::Product => &[Commands::GetProductString1, Commands::GetProductString2],
+            UsbString::Serial => &[Commands::GetSerialString],
+        }
+    }
+
+    /// Set commands and lock flags for each of the blocks making up the string
+    fn set_commands(&self) -> &'static [(Commands, LockByte)] {
+        match self {
+            UsbString::Manufacturer => &[
+                (Commands::SetManufacturingString1, LockByte::MANUFACTURER_STRING_1),
+                (Commands::SetManufacturingString2, LockByte::MANUFACTURER_STRING_2),
+            ],
+            UsbString::Product => &[
+                (Commands::SetProductString1, LockByte::PRODUCT_STRING_1),
+                (Commands::SetProductString2, LockByte::PRODUCT_STRING_2),
+            ],
+            UsbString::Serial => &[
+                (Commands::SetSerialString, LockByte::SERIAL_STRING),
+            ],
+        }
+    }
+
+    /// Encode a string as a USB string descriptor, returning the descriptor and the
+    /// number of blocks required to store it
+    pub fn encode(&self, value: &str) -> Result<(Vec<u8>, usize), Error> {
+        let chars: Vec<u16> = value.encode_utf16().collect();
+        if chars.len() > self.max_len() {
+            return Err(Error::StringLength(self.max_len()))
+        }
+
+        let len = 2 + chars.len() * 2;
+        let blocks = len.div_ceil(STRING_BLOCK_SIZE);
+
+        let mut buff = vec![0u8; self.get_commands().len() * STRING_BLOCK_SIZE];
+        buff[0] = len as u8;
+        buff[1] = STRING_DESCRIPTOR_TYPE;
+        for (i, c) in chars.iter().enumerate() {
+            LE::write_u16(&mut buff[2 + i * 2..], *c);
+        }
+
+        Ok((buff, blocks))
+    }
+
+    /// Decode a USB string descriptor
+    pub fn decode(&self, buff: &[u8]) -> Result<String, Error> {
+        // Descriptor length includes the two byte header
+        if buff.len() < 2 || buff[0] < 2 || buff[1] != STRING_DESCRIPTOR_TYPE {
+            return Err(Error::InvalidResponse)
+        }
+
+        let len = (buff[0] as usize).min(buff.len()).min(2 + self.max_len() * 2);
+        let chars: Vec<u16> = buff[2..len].chunks_exact(2).map(LE::read_u16).collect();
+
+        String::from_utf16(&chars).map_err(|_| Error::InvalidResponse)
+    }
+}
+
+impl FromStr for UsbString {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "manufacturer" => Ok(Self::Manufacturer),
+            "product" => Ok(Self::Product),
+            "serial" => Ok(Self::Serial),
+            _ => Err("Unrecognised string, try 'manufacturer', 'product', or 'serial'".to_string()),
+        }
+    }
+}
+
+/// Number of GPIO pins
+pub const GPIO_COUNT: usize = 11;
+
+/// GPIO pin functions, as configured in the OTP pin configuration
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PinFunction {
+    /// GPIO input (all pins)
+    Input,
+    /// GPIO open-drain output (all pins)
+    OpenDrain,
+    /// GPIO push-pull output (all pins)
+    PushPull,
+    /// SPI chip select for the matching channel (all pins)
+    ChipSelect,
+    /// Active low ready-to-read input (GPIO3)
+    NotRtr,
+    /// Active high ready-to-read input (GPIO3)
+    Rtr,
+    /// Event counter input (GPIO4)
+    EventCounter(EventCounterMode),
+    /// Clock output (GPIO5)
+    ClockOut,
+    /// SPI activity output (GPIO8)
+    SpiActivity,
+    /// Active high suspend output (GPIO9)
+    Suspend,
+    /// Active low suspend output (GPIO10)
+    NotSuspend,
+}
+
+impl PinFunction {
+    /// Check whether a given pin supports this function
+    pub fn supported(&self, pin: u8) -> bool {
+        match self {
+            PinFunction::Input | PinFunction::OpenDrain | PinFunction::PushPull | PinFunction::ChipSelect => (pin as usize) < GPIO_COUNT,
+            PinFunction::NotRtr | PinFunction::Rtr => pin == 3,
+            PinFunction::EventCounter(_) => pin == 4,
+            PinFunction::ClockOut => pin == 5,
+            PinFunction::SpiActivity => pin == 8,
+            PinFunction::Suspend => pin == 9,
+            PinFunction::NotSuspend => pin == 10,
+        }
+    }
+
+    /// Check whether a pin with this function may be used as a GPIO.
+    /// Chip select pins may be used as GPIO unless claimed by an SPI channel.
+    pub fn allows_gpio(&self) -> bool {
+        matches!(self, PinFunction::Input | PinFunction::OpenDrain | PinFunction::PushPull | PinFunction::ChipSelect)
+    }
+
+    fn encode(&self) -> u8 {
+        match self {
+            PinFunction::Input => 0x00,
+            PinFunction::OpenDrain => 0x01,
+            PinFunction::PushPull => 0x02,
+            PinFunction::ChipSelect => 0x03,
+            PinFunction::NotRtr => 0x04,
+            PinFunction::Rtr => 0x05,
+            PinFunction::EventCounter(m) => *m as u8,
+            PinFunction::ClockOut | PinFunction::SpiActivity | PinFunction::Suspend | PinFunction::NotSuspend => 0x04,
+        }
+    }
+
+    fn decode(pin: u8, v: u8) -> Result<Self, Error> {
+        let f = match (pin, v) {
+            (_, 0x00) => PinFunction::Input,
+            (_, 0x01) => PinFunction::OpenDrain,
+            (_, 0x02) => PinFunction::PushPull,
+            (_, 0x03) => PinFunction::ChipSelect,
+            (3, 0x04) => PinFunction::NotRtr,
+            (3, 0x05) => PinFunction::Rtr,
+            (4, 0x04) => PinFunction::EventCounter(EventCounterMode::RisingEdge),
+            (4, 0x05) => PinFunction::EventCounter(EventCounterMode::FallingEdge),
+            (4, 0x06) => PinFunction::EventCounter(EventCounterMode::NegativePulse),
+            (4, 0x07) => PinFunction::EventCounter(EventCounterMode::PositivePulse),
+            (5, 0x04) => PinFunction::ClockOut,
+            (8, 0x04) => PinFunction::SpiActivity,
+            (9, 0x04) => PinFunction::Suspend,
+            (10, 0x04) => PinFunction::NotSuspend,
+            _ => return Err(Error::InvalidResponse),
+        };
+
+        Ok(f)
+    }
+}
+
+impl FromStr for PinFunction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "input" => Ok(Self::Input),
+            "open-drain" => Ok(Self::OpenDrain),
+            "push-pull" => Ok(Self::PushPull),
+            "chip-select" => Ok(Self::ChipSelect),
+            "n-rtr" => Ok(Self::NotRtr),
+            "rtr" => Ok(Self::Rtr),
+            "event-rising" => Ok(Self::EventCounter(EventCounterMode::RisingEdge)),
+            "event-falling" => Ok(Self::EventCounter(EventCounterMode::FallingEdge)),
+            "event-negative-pulse" => Ok(Self::EventCounter(EventCounterMode::NegativePulse)),
+            "event-positive-pulse" => Ok(Self::EventCounter(EventCounterMode::PositivePulse)),
+            "clock-out" => Ok(Self::ClockOut),
+            "spi-activity" => Ok(Self::SpiActivity),
+            "suspend" => Ok(Self::Suspend),
+            "n-suspend" => Ok(Self::NotSuspend),
+            _ => Err("Unrecognised pin function, try 'input', 'open-drain', 'push-pull', 'chip-select', 'n-rtr', 'rtr', \
+                'event-rising', 'event-falling', 'event-negative-pulse', 'event-positive-pulse', 'clock-out', \
+                'spi-activity', 'suspend', or 'n-suspend'".to_string()),
+        }
+    }
+}
+
+/// Size of the pin configuration
+pub const PIN_CONFIG_SIZE: usize = 20;
+
+/// Flag in each per-pin configuration byte setting the power-on output level
+const PIN_RESET_LEVEL: u8 = 0x80;
+
+/// Pin configuration, stored in OTP memory and applied at power-on
+#[derive(Debug, PartialEq, Clone)]
+pub struct PinConfig {
+    functions: [PinFunction; GPIO_COUNT],
+    /// Output levels applied at power-on
+    pub reset_level: GpioLevels,
+    /// Pin levels while the device is suspended
+    pub suspend_level: GpioLevels,
+    /// Pin modes while the device is suspended (set for push-pull, clear for open-drain)
+    pub suspend_mode: GpioLevels,
+    /// Pins that may wake the device from suspend
+    pub wakeup_mask: GpioLevels,
+    /// Levels on wakeup pins that wake the device from suspend
+    pub wakeup_match: GpioLevels,
+    /// Power-on clock output divider (24 MHz / divider, 0 for 256)
+    pub clock_divider: u8,
+}
+
+impl Default for PinConfig {
+    fn default() -> Self {
+        Self {
+            functions: [PinFunction::Input; GPIO_COUNT],
+            reset_level: GpioLevels::empty(),
+            suspend_level: GpioLevels::empty(),
+            suspend_mode: GpioLevels::empty(),
+            wakeup_mask: GpioLevels::empty(),
+            wakeup_match: GpioLevels::empty(),
+            clock_divider: 0,
+        }
+    }
+}
+
+impl PinConfig {
+    /// Fetch the function for a given pin
+    pub fn function(&self, pin: u8) -> Result<PinFunction, Error> {
+        self.functions.get(pin as usize).copied().ok_or(Error::InvalidPin(pin))
+    }
+
+    /// Set the function for a given pin, checking the function is supported by the pin
+    pub fn set_function(&mut self, pin: u8, function: PinFunction) -> Result<(), Error> {
+        if (pin as usize) >= GPIO_COUNT {
+            return Err(Error::InvalidPin(pin))
+        }
+        if !function.supported(pin) {
+            return Err(Error::UnsupportedFunction(pin, function))
+        }
+
+        self.functions[pin as usize] = function;
+
+        Ok(())
+    }
+
+    /// Fetch the functions for all pins
+    pub fn functions(&self) -> &[PinFunction] {
+        &self.functions
+    }
+
+    /// Fetch the pin modes applied at power-on (set for push-pull, clear for open-drain or input)
+    pub fn reset_mode(&self) -> GpioLevels {
+        self.functions.iter().zip(GPIO_PINS.iter())
+            .filter(|(f, _)| **f == PinFunction::PushPull)
+            .fold(GpioLevels::empty(), |m, (_, p)| m | *p)
+    }
+
+    /// Decode a pin configuration from a Get Pin Config response
+    pub fn decode(buff: &[u8]) -> Result<Self, Error> {
+        if buff.len() < PIN_CONFIG_SIZE {
+            return Err(Error::InvalidResponse)
+        }
+
+        let mut functions = [PinFunction::Input; GPIO_COUNT];
+        let mut reset_level = GpioLevels::empty();
+        for (i, f) in functions.iter_mut().enumerate() {
+            *f = PinFunction::decode(i as u8, buff[i] & !PIN_RESET_LEVEL)?;
+            reset_level.set(GPIO_PINS[i], buff[i] & PIN_RESET_LEVEL != 0);
+        }
+
+        Ok(Self {
+            functions,
+            reset_level,
+            suspend_level: GpioLevels::decode(&buff[11..]),
+            suspend_mode: GpioLevels::decode(&buff[13..]),
+            wakeup_mask: GpioLevels::decode(&buff[15..]),
+            wakeup_match: GpioLevels::decode(&buff[17..]),
+            clock_divider: buff[19],
+        })
+    }
+
+    /// Encode a pin configuration for a Set Pin Config request
+    pub fn encode(&self) -> [u8; PIN_CONFIG_SIZE] {
+        let mut buff = [0u8; PIN_CONFIG_SIZE];
+
+        for (i, f) in self.functions.iter().enumerate() {
+            buff[i] = f.encode();
+            if self.reset_level.contains(GPIO_PINS[i]) {
+                buff[i] |= PIN_RESET_LEVEL;
+            }
+        }
+
+        buff[11..13].copy_from_slice(&self.suspend_level.encode());
+        buff[13..15].copy_from_slice(&self.suspend_mode.encode());
+        buff[15..17].copy_from_slice(&self.wakeup_mask.encode());
+        buff[17..19].copy_from_slice(&self.wakeup_match.encode());
+        buff[19] = self.clock_divider;
+
+        buff
+    }
+}
+
+/// USB power mode
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PowerMode {
+    /// Bus powered, internal regulator enabled
+    BusPowered = 0x00,
+    /// Self powered, internal regulator disabled
+    SelfPowered = 0x01,
+    /// Self powered, internal regulator enabled
+    SelfPoweredRegulator = 0x02,
+}
+
+impl std::convert::TryFrom<u8> for PowerMode {
+    type Error = Error;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            0x00 => Ok(PowerMode::BusPowered),
+            0x01 => Ok(PowerMode::SelfPowered),
+            0x02 => Ok(PowerMode::SelfPoweredRegulator),
+            _ => Err(Error::InvalidResponse),
+        }
+    }
+}
+
+impl FromStr for PowerMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bus" => Ok(Self::BusPowered),
+            "self" => Ok(Self::SelfPowered),
+            "self-regulator" => Ok(Self::SelfPoweredRegulator),
+            _ => Err("Unrecognised power mode, try 'bus', 'self', or 'self-regulator'".to_string()),
+        }
+    }
+}
+
+/// USB transfer priority
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TransferPriority {
+    /// Prioritise reads over writes
+    HighPriorityRead = 0x00,
+    /// Prioritise writes over reads
+    HighPriorityWrite = 0x01,
+}
+
+impl std::convert::TryFrom<u8> for TransferPriority {
+    type Error = Error;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            0x00 => Ok(TransferPriority::HighPriorityRead),
+            0x01 => Ok(TransferPriority::HighPriorityWrite),
+            _ => Err(Error::InvalidResponse),
+        }
+    }
+}
+
+impl FromStr for TransferPriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Self::HighPriorityRead),
+            "write" => Ok(Self::HighPriorityWrite),
+            _ => Err("Unrecognised transfer priority, try 'read' or 'write'".to_string()),
+        }
+    }
+}
+
+bitflags!(
+    /// Mask for selecting which USB configuration fields are written
+    pub struct UsbConfigMask: u8 {
+        const VID               = 1 << 0;
+        const PID               = 1 << 1;
+        const MAX_POWER         = 1 << 2;
+        const POWER_MODE        = 1 << 3;
+        const RELEASE_VERSION   = 1 << 4;
+        const TRANSFER_PRIORITY = 1 << 7;
+    }
+);
+
+impl UsbConfigMask {
+    /// Fetch the lock byte fields corresponding to the masked USB configuration fields
+    pub fn lock_fields(&self) -> LockByte {
+        let mut l = LockByte::empty();
+
+        l.set(LockByte::VID, self.contains(UsbConfigMask::VID));
+        l.set(LockByte::PID, self.contains(UsbConfigMask::PID));
+        l.set(LockByte::MAX_POWER, self.contains(UsbConfigMask::MAX_POWER));
+        l.set(LockByte::POWER_MODE, self.contains(UsbConfigMask::POWER_MODE));
+        l.set(LockByte::RELEASE_VERSION, self.contains(UsbConfigMask::RELEASE_VERSION));
+        l.set(LockByte::TRANSFER_PRIORITY, self.contains(UsbConfigMask::TRANSFER_PRIORITY));
+
+        l
+    }
+}
+
+/// Size of the USB configuration (excluding the mask byte used when writing)
+const USB_CONFIG_SIZE: usize = 9;
+
+/// Maximum bus current that can be requested in mA
+pub const MAX_POWER_LIMIT_MA: u16 = 510;
+
+/// USB configuration, stored in OTP memory
+#[derive(Debug, PartialEq, Clone)]
+pub struct UsbConfig {
+    /// USB Vendor ID
+    pub vid: u16,
+    /// USB Product ID
+    pub pid: u16,
+    /// Maximum bus current in units of 2 mA
+    pub max_power: u8,
+    /// Bus / self powered mode
+    pub power_mode: PowerMode,
+    /// Release version, major version in the upper byte and minor in the lower
+    pub release_version: u16,
+    /// Read / write transfer priority
+    pub transfer_priority: TransferPriority,
+}
+
+impl UsbConfig {
+    /// Fetch the maximum bus current in mA
+    pub fn max_power_ma(&self) -> u16 {
+        self.max_power as u16 * 2
+    }
+
+    /// Set the maximum bus current in mA (rounded down to 2 mA units), up to 510 mA
+    pub fn set_max_power_ma(&mut self, ma: u16) -> Result<(), Error> {
+        if ma > MAX_POWER_LIMIT_MA {
+            return Err(Error::InvalidMaxPower(ma))
+        }
+
+        self.max_power = (ma / 2) as u8;
+
+        Ok(())
+    }
+
+    /// Decode a USB configuration from a Get USB Config response
+    pub fn decode(buff: &[u8]) -> Result<Self, Error> {
+        use std::convert::TryFrom;
+
+        if buff.len() < USB_CONFIG_SIZE {
+            return Err(Error::InvalidResponse)
+        }
+
+        Ok(Self {
+            vid: LE::read_u16(&buff[0..]),
+            pid: LE::read_u16(&buff[2..]),
+            max_power: buff[4],
+            power_mode: PowerMode::try_from(buff[5])?,
+            release_version: (buff[6] as u16) << 8 | buff[7] as u16,
+            transfer_priority: TransferPriority::try_from(buff[8])?,
+        })
+    }
+
+    /// Encode a USB configuration (and field mask) for a Set USB Config request
+    pub fn encode(&self, mask: UsbConfigMask) -> [u8; USB_CONFIG_SIZE + 1] {
+        let mut buff = [0u8; USB_CONFIG_SIZE + 1];
+
+        LE::write_u16(&mut buff[0..], self.vid);
+        LE::write_u16(&mut buff[2..], self.pid);
+        buff[4] = self.max_power;
+        buff[5] = self.power_mode as u8;
+        buff[6] = (self.release_version >> 8) as u8;
+        buff[7] = self.release_version as u8;
+        buff[8] = self.transfer_priority as u8;
+        buff[9] = mask.bits();
+
+        buff
+    }
+}
+
+impl Inner {
+    /// Fetch the lock byte, indicating which OTP fields are still writable
+    pub(crate) fn get_lock_byte(&mut self) -> Result<LockByte, Error> {
+        let lock = LockByte::from_bits_truncate(self.get_lock_byte_raw()?);
+
+        trace!("Get lock byte: {:?}", lock);
+
+        Ok(lock)
+    }
+
+    /// Fetch the raw lock byte value, including reserved bits
+    fn get_lock_byte_raw(&mut self) -> Result<u16, Error> {
+        let mut buff = [0u8; 2];
+
+        self.control_read_exact(Commands::GetLockByte, 0, 0, &mut buff)?;
+
+        Ok(LE::read_u16(&buff))
+    }
+
+    /// Permanently lock the confirmed OTP fields
+    pub(crate) fn set_lock_byte(&mut self, confirm: LockConfirmation) -> Result<(), Error> {
+        let current = self.get_lock_byte_raw()?;
+
+        // Cleared bits lock the corresponding fields
+        let value = confirm.lock_value(current);
+        let mut cmd = [0u8; 2];
+        LE::write_u16(&mut cmd, value);
+
+        warn!("Locking OTP fields: {:?} (lock byte: {:#06x})", confirm.fields(), value);
+
+        self.control_write_once(Commands::SetLockByte, OTP_MEMORY_KEY, 0, &cmd)?;
+
+        Ok(())
+    }
+
+    /// Check the specified OTP fields are unlocked (still writable)
+    pub(crate) fn check_unlocked(&mut self, fields: LockByte) -> Result<(), Error> {
+        let lock = self.get_lock_byte()?;
+
+        let locked = fields - lock;
+        if !locked.is_empty() {
+            error!("OTP fields {:?} are locked (lock byte: {:?})", locked, lock);
+            return Err(Error::Locked(locked))
+        }
+
+        Ok(())
+    }
+
+    /// Fetch a programmable USB string
+    pub(crate) fn get_usb_string(&mut self, field: UsbString) -> Result<String, Error> {
+        let commands = field.get_commands();
+        let mut buff = vec![0u8; commands.len() * STRING_BLOCK_SIZE];
+
+        for (c, b) in commands.iter().zip(buff.chunks_mut(STRING_BLOCK_SIZE)) {
+            self.control_read_exact(*c, 0, 0, b)?;
+        }
+
+        let value = field.decode(&buff)?;
+
+        trace!("Get USB string {:?}: '{}'", field, value);
+
+        Ok(value)
+    }
+
+    /// Write a programmable USB string, only the blocks required to store the string are written
+    pub(crate) fn set_usb_string(&mut self, field: UsbString, value: &str) -> Result<(), Error> {
+        let (buff, blocks) = field.encode(value)?;
+        let commands = &field.set_commands()[..blocks];
+
+        // Check the required blocks are still writable
+        let fields = commands.iter().fold(LockByte::empty(), |a, (_c, l)| a | *l);
+        self.check_unlocked(fields)?;
+
+        debug!("Set USB string {:?}: '{}'", field, value);
+
+        for ((c, _l), b) in commands.iter().zip(buff.chunks(STRING_BLOCK_SIZE)) {
+            self.control_write_once(*c, OTP_MEMORY_KEY, 0, b)?;
+        }
+
+        Ok(())
+    }
+
+    /// Fetch the pin configuration
+    pub(crate) fn get_pin_config(&mut self) -> Result<PinConfig, Error> {
+        let mut buff = [0u8; PIN_CONFIG_SIZE];
+
+        let n = self.control_read(Commands::GetPinConfig, 0, 0, &mut buff)?;
+
+        let config = PinConfig::decode(&buff[..n])?;
+
+        trace!("Get pin config: {:?}", config);
+
+        Ok(config)
+    }
+
+    /// Write the pin configuration, this takes effect on the next reset
+    pub(crate) fn set_pin_config(&mut self, config: &PinConfig) -> Result<(), Error> {
+        self.check_unlocked(LockByte::PIN_CONFIG)?;
+
+        let cmd = config.encode();
+
+        debug!("Set pin config: {:?}", config);
+
+        self.control_write_once(Commands::SetPinConfig, OTP_MEMORY_KEY, 0, &cmd)?;
+
+        Ok(())
+    }
+
+    /// Refresh the cached pin configuration, leaving it unknown if it can not be read
+    pub(crate) fn load_pin_config(&mut self) {
+        self.pin_config = match self.get_pin_config() {
+            Ok(c) => Some(c),
+            Err(e) => {
+                warn!("Failed to read pin configuration, this will be retried on use: {:?}", e);
+                None
+            },
+        };
+    }
+
+    /// Fetch the configured function for a pin, re-reading the pin configuration if it is unknown
+    pub(crate) fn pin_function(&mut self, pin: u8) -> Result<PinFunction, Error> {
+        if self.pin_config.is_none() {
+            let config = self.get_pin_config()?;
+            self.pin_config = Some(config);
+        }
+
+        self.pin_config.as_ref().map_or(Err(Error::InvalidResponse), |c| c.function(pin))
+    }
+
+    /// Fetch the USB configuration
+    pub(crate) fn get_usb_config(&mut self) -> Result<UsbConfig, Error> {
+        let mut buff = [0u8; USB_CONFIG_SIZE];
+
+        let n = self.control_read(Commands::GetUsbConfig, 0, 0, &mut buff)?;
+
+        let config = UsbConfig::decode(&buff[..n])?;
+
+        trace!("Get USB config: {:?}", config);
+
+        Ok(config)
+    }
+
+    /// Write the USB configuration fields selected by `mask`
+    pub(crate) fn set_usb_config(&mut self, config: &UsbConfig, mask: UsbConfigMask) -> Result<(), Error> {
+        self.check_unlocked(mask.lock_fields())?;
+
+        let cmd = config.encode(mask);
+
+        debug!("Set USB config: {:?} (mask: {:?})", config, mask);
+
+        self.control_write_once(Commands::SetUsbConfig, OTP_MEMORY_KEY, 0, &cmd)?;
+
+        Ok(())
+    }
+
+    /// Read a single block of the PROM configuration
+    pub(crate) fn get_prom_block(&mut self, block: u8, buff: &mut [u8]) -> Result<(), Error> {
+        if buff.len() != PROM_BLOCK_SIZE {
+            return Err(Error::PromLength(buff.len()))
+        }
+
+        self.control_read_exact(Commands::GetPromConfig, 0, block as u16, buff)?;
+
+        trace!("PROM read block: {} (data: {:02x?})", block, buff);
+
+        Ok(())
+    }
+
+    /// Write a single block of the PROM configuration
+    pub(crate) fn set_prom_block(&mut self, block: u8, data: &[u8]) -> Result<(), Error> {
+        if data.len() != PROM_BLOCK_SIZE {
+            return Err(Error::PromLength(data.len()))
+        }
+
+        debug!("PROM write block: {} (data: {:02x?})", block, data);
+
+        let n = self.control_write_once(Commands::SetPromConfig, OTP_MEMORY_KEY, block as u16, data)?;
+        Error::check_len(Op::Control(Commands::SetPromConfig), n, PROM_BLOCK_SIZE)?;
+
+        Ok(())
+    }
+
+    /// Read the full PROM configuration
+    pub(crate) fn get_prom_config(&mut self) -> Result<PromConfig, Error> {
+        let mut config = PromConfig::default();
+
+        for (i, b) in config.data.chunks_mut(PROM_BLOCK_SIZE).enumerate() {
+            self.get_prom_block(i as u8, b)?;
+        }
+
+        Ok(config)
+    }
+
+    /// Write the full PROM configuration block-by-block, reading back each block to verify the write.
+    /// Blocks matching the current device configuration are skipped.
+    pub(crate) fn set_prom_config(&mut self, current: &PromConfig, config: &PromConfig) -> Result<(), Error> {
+        // The PROM image covers all OTP fields, so these must all be writable
+        self.check_unlocked(LockByte::all())?;
+
+        // Check every changed block before writing anything
+        current.check_writable(config)?;
+
+        let mut readback = [0u8; PROM_BLOCK_SIZE];
+
+        for (i, (c, n)) in current.blocks().zip(config.blocks()).enumerate() {
+            if c == n {
+                trace!("PROM block {} unchanged, skipping", i);
+                continue;
+            }
+
+            self.set_prom_block(i as u8, n)?;
+
+            self.get_prom_block(i as u8, &mut readback)?;
+            if &readback[..] != n {
+                error!("PROM verify failed for block {} (expected: {:02x?} actual: {:02x?})", i, n, readback);
+                return Err(Error::PromVerify(i as u8))
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrorKind;
+
+    #[test]
+    fn prom_check_writable() {
+        let current = PromConfig::default();
+
+        // Clearing bits is always possible
+        let mut cleared = current.clone();
+        cleared.block_mut(2).unwrap()[0] = 0x0F;
+        assert!(current.check_writable(&cleared).is_ok());
+        assert!(cleared.check_writable(&cleared).is_ok());
+
+        // Setting cleared bits is not, and is reported for the first failing block
+        let mut set = cleared.clone();
+        set.block_mut(2).unwrap()[0] = 0x1F;
+        set.block_mut(5).unwrap()[3] = 0x00;
+        assert_eq!(cleared.check_writable(&set).unwrap_err().kind(), ErrorKind::InvalidArgument);
+        assert!(matches!(cleared.check_writable(&set), Err(Error::PromUnwritable(2))));
+    }
+
+    #[test]
+    fn usb_config_round_trip() {
+        let c = UsbConfig {
+            vid: 0x10c4,
+            pid: 0x87a0,
+            max_power: 0x32,
+            power_mode: PowerMode::SelfPoweredRegulator,
+            release_version: 0x0102,
+            transfer_priority: TransferPriority::HighPriorityWrite,
+        };
+
+        let mask = UsbConfigMask::VID | UsbConfigMask::TRANSFER_PRIORITY;
+        let buff = c.encode(mask);
+
+        // Fields are little endian, except the release version (major, minor)
+        assert_eq!(buff, [0xc4, 0x10, 0xa0, 0x87, 0x32, 0x02, 0x01, 0x02, 0x01, 0x81]);
+        assert_eq!(UsbConfig::decode(&buff[..9]).unwrap(), c);
+
+        assert_eq!(mask.lock_fields(), LockByte::VID | LockByte::TRANSFER_PRIORITY);
+    }
+
+    #[test]
+    fn usb_config_malformed() {
+        assert!(matches!(UsbConfig::decode(&[0u8; 8]), Err(Error::InvalidResponse)));
+
+        // Unknown power modes and transfer priorities are rejected
+        assert!(matches!(UsbConfig::decode(&[0, 0, 0, 0, 0, 0x03, 0, 0, 0]), Err(Error::InvalidResponse)));
+        assert!(matches!(UsbConfig::decode(&[0, 0, 0, 0, 0, 0, 0, 0, 0x02]), Err(Error::InvalidResponse)));
+    }
+
+    #[test]
+    fn usb_config_max_power() {
+        let mut c = UsbConfig::decode(&[0, 0, 0, 0, 0x32, 0, 0, 0, 0]).unwrap();
+        assert_eq!(c.max_power_ma(), 100);
+
+        c.set_max_power_ma(MAX_POWER_LIMIT_MA).unwrap();
+        assert_eq!(c.max_power, 0xFF);
+
+        // Currents that do not fit in 2 mA units are rejected rather than clamped
+        assert!(matches!(c.set_max_power_ma(512), Err(Error::InvalidMaxPower(512))));
+        assert_eq!(c.max_power, 0xFF);
+    }
+
+    #[test]
+    fn usb_string_round_trip() {
+        for field in [UsbString::Manufacturer, UsbString::Product, UsbString::Serial].iter() {
+            let max = "x".repeat(field.max_len());
+
+            for s in ["", "CP2130 \u{00b5}", max.as_str()].iter() {
+                let (buff, blocks) = field.encode(s).unwrap();
+                assert_eq!(field.decode(&buff).unwrap(), *s);
+
+                // Only the blocks holding the descriptor are required
+                assert!(blocks * STRING_BLOCK_SIZE >= 2 + s.encode_utf16().count() * 2);
+                assert_eq!(field.decode(&buff[..blocks * STRING_BLOCK_SIZE]).unwrap(), *s);
+            }
+
+            let long = "x".repeat(field.max_len() + 1);
+            assert!(matches!(field.encode(&long), Err(Error::StringLength(_))));
+        }
+    }
+
+    #[test]
+    fn usb_string_malformed() {
+        let f = UsbString::Serial;
+
+        // Short buffers, short lengths, and wrong descriptor types are rejected
+        for buff in [&[][..], &[0x02], &[0x00, 0x03], &[0x01, 0x03, 0x41], &[0x04, 0x01, 0x41, 0x00], &[0xFF; 64]].iter() {
+            assert!(matches!(f.decode(buff), Err(Error::InvalidResponse)));
+        }
+
+        // Lengths beyond the buffer or field are truncated
+        assert_eq!(f.decode(&[0xFF, 0x03, 0x41, 0x00]).unwrap(), "A");
+
+        // Invalid UTF-16 is rejected
+        assert!(matches!(f.decode(&[0x04, 0x03, 0x00, 0xD8]), Err(Error::InvalidResponse)));
+    }
+
+    #[test]
+    fn pin_functions() {
+        let mut c = PinConfig::default();
+
+        // GPIO and chip select functions are available on all pins
+        for pin in 0..=10 {
+            c.set_function(pin, PinFunction::PushPull).unwrap();
+            c.set_function(pin, PinFunction::ChipSelect).unwrap();
+        }
+
+        // Alternate functions are only available on specific pins
+        c.set_function(5, PinFunction::ClockOut).unwrap();
+        c.set_function(4, PinFunction::EventCounter(EventCounterMode::RisingEdge)).unwrap();
+        assert!(c.set_function(6, PinFunction::ClockOut).is_err());
+        assert!(c.set_function(3, PinFunction::SpiActivity).is_err());
+
+        // Pins outside the valid range are rejected
+        assert!(c.set_function(11, PinFunction::Input).is_err());
+        assert!(c.function(11).is_err());
+
+        assert_eq!(c.function(5).unwrap(), PinFunction::ClockOut);
+        assert!(!PinFunction::ClockOut.allows_gpio());
+    }
+
+    #[test]
+    fn pin_config_round_trip() {
+        let mut c = PinConfig::default();
+        c.set_function(0, PinFunction::ChipSelect).unwrap();
+        c.set_function(3, PinFunction::NotRtr).unwrap();
+        c.set_function(4, PinFunction::EventCounter(EventCounterMode::PositivePulse)).unwrap();
+        c.set_function(5, PinFunction::ClockOut).unwrap();
+        c.set_function(10, PinFunction::NotSuspend).unwrap();
+        c.suspend_level = GpioLevels::GPIO_0;
+        c.wakeup_mask = GpioLevels::GPIO_10;
+        c.clock_divider = 12;
+
+        let buff = c.encode();
+
+        assert_eq!(&buff[..11], &[0x03, 0x00, 0x00, 0x04, 0x07, 0x04, 0x00, 0x00, 0x00, 0x00, 0x04]);
+        assert_eq!(&buff[11..], &[0x00, 0x08, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00, 12]);
+
+        assert_eq!(PinConfig::decode(&buff).unwrap(), c);
+    }
+
+    #[test]
+    fn pin_config_malformed() {
+        assert!(matches!(PinConfig::decode(&[0u8; 19]), Err(Error::InvalidResponse)));
+
+        // Alternate function values are only valid on their matching pins
+        let mut buff = PinConfig::default().encode();
+        buff[6] = 0x04;
+        assert!(matches!(PinConfig::decode(&buff), Err(Error::InvalidResponse)));
+    }
+
+    #[test]
+    fn pin_config_reset_state() {
+        let mut c = PinConfig::default();
+        c.set_function(1, PinFunction::PushPull).unwrap();
+        c.set_function(2, PinFunction::OpenDrain).unwrap();
+        c.reset_level = GpioLevels::GPIO_1 | GpioLevels::GPIO_10;
+
+        // Power-on levels are flagged in the per-pin configuration bytes
+        let buff = c.encode();
+        assert_eq!(&buff[..11], &[0x00, 0x82, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80]);
+
+        let d = PinConfig::decode(&buff).unwrap();
+        assert_eq!(d, c);
+        assert_eq!(d.reset_mode(), GpioLevels::GPIO_1);
+    }
+
+    #[test]
+    fn lock_byte_value() {
+        let c = LockConfirmation::irreversibly_lock(LockByte::SERIAL_STRING | LockByte::PIN_CONFIG);
+
+        // Only the confirmed fields are cleared, reserved bits are preserved
+        assert_eq!(c.lock_value(0xFFFF), 0xF3FF);
+        assert_eq!(c.lock_value(0xF7FF), 0xF3FF);
+
+        // Bits are never set, as OTP bits can not be set once cleared
+        assert_eq!(c.lock_value(0x0000), 0x0000);
+        assert_eq!(LockConfirmation::irreversibly_lock(LockByte::empty()).lock_value(0x1234), 0x1234);
+
+        // Unprogrammed lock bytes read as all fields unlocked
+        assert_eq!(LockByte::from_bits_truncate(0xFFFF), LockByte::all());
+        assert_eq!(LockByte::all().bits(), 0x0FFF);
+    }
+}
@@ -0,0 +1,186 @@
+//! CP2130 SPI transfer pacing
+//!
+//! Bulk writes complete once the device has accepted the data, which can be
+//! well before it has been clocked out. Pacing tracks when the bus will be idle
+//! so subsequent operations wait (by sleeping) only when required.
+//!
+//! Copyright 2019 Ryan Kurte
+
+use std::time::{Duration, Instant};
+
+use crate::device::SPI_OP_DELAY_US;
+
+/// Clock and sleep strategy used for pacing, replaceable for testing
+pub trait Clock: Send {
+    /// Fetch the current time
+    fn now(&self) -> Instant;
+
+    /// Sleep for the provided duration
+    fn sleep(&self, d: Duration);
+}
+
+/// Default clock using the system monotonic clock and thread sleeps
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, d: Duration) {
+        std::thread::sleep(d)
+    }
+}
+
+/// Pacer tracks when the SPI bus is expected to be idle
+pub struct Pacer {
+    clock: Box<dyn Clock>,
+    margin: Duration,
+    busy_until: Option<Instant>,
+}
+
+impl Pacer {
+    /// Create a new pacer with the provided clock and safety margin
+    pub fn new<C: Clock + 'static>(clock: C, margin: Duration) -> Self {
+        Self{clock: Box::new(clock), margin, busy_until: None}
+    }
+
+    /// Fetch the safety margin added to each transfer
+    pub fn margin(&self) -> Duration {
+        self.margin
+    }
+
+    /// Set the safety margin added to each transfer
+    pub fn set_margin(&mut self, margin: Duration) {
+        self.margin = margin;
+    }
+
+    /// Replace the clock used for pacing
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Mark the bus busy for the provided transfer time (plus margin) from now
+    pub fn busy(&mut self, transfer_time: Duration) {
+        // Saturate rather than overflow for unrepresentable (ie. misconfigured) delays
+        let now = self.clock.now();
+        let until = now.checked_add(transfer_time.saturating_add(self.margin))
+            .unwrap_or_else(|| now + Duration::from_secs(u32::MAX as u64));
+
+        self.busy_until = match self.busy_until {
+            Some(t) if t > until => Some(t),
+            _ => Some(until),
+        };
+    }
+
+    /// Mark the bus idle, for use when the device has reported completion
+    pub fn idle(&mut self) {
+        self.busy_until = None;
+    }
+
+    /// Remaining time until the bus is expected to be idle
+    pub fn remaining(&self) -> Duration {
+        match self.busy_until {
+            Some(t) => t.saturating_duration_since(self.clock.now()),
+            None => Duration::from_secs(0),
+        }
+    }
+
+    /// Sleep until the bus is expected to be idle
+    pub fn wait(&mut self) {
+        let remaining = self.remaining();
+        if remaining > Duration::from_secs(0) {
+            trace!("Pacing wait ({} us)", remaining.as_micros());
+            self.clock.sleep(remaining);
+        }
+
+        self.busy_until = None;
+    }
+}
+
+impl Default for Pacer {
+    fn default() -> Self {
+        Self::new(SystemClock, Duration::from_micros(SPI_OP_DELAY_US))
+    }
+}
+
+/// Fake clock for testing, recording sleeps
+#[cfg(test)]
+pub(crate) mod fake {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// Fake clock, advanced manually or by sleeping (clones share state)
+    #[derive(Clone)]
+    pub(crate) struct FakeClock {
+        now: Arc<Mutex<Instant>>,
+        sleeps: Arc<Mutex<Vec<Duration>>>,
+    }
+
+    impl FakeClock {
+        pub fn new() -> Self {
+            Self{now: Arc::new(Mutex::new(Instant::now())), sleeps: Arc::new(Mutex::new(vec![]))}
+        }
+
+        pub fn advance(&self, d: Duration) {
+            *self.now.lock().unwrap() += d;
+        }
+
+        pub fn sleeps(&self) -> Vec<Duration> {
+            self.sleeps.lock().unwrap().drain(..).collect()
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            *self.now.lock().unwrap()
+        }
+
+        fn sleep(&self, d: Duration) {
+            self.sleeps.lock().unwrap().push(d);
+            self.advance(d);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::fake::FakeClock;
+    use crate::device::SpiClock;
+
+    #[test]
+    fn pacing() {
+        let clock = FakeClock::new();
+        let mut p = Pacer::new(clock.clone(), Duration::from_micros(100));
+
+        // Idle bus does not wait
+        p.wait();
+        assert_eq!(clock.sleeps(), vec![]);
+
+        // Wait for the remainder of the transfer plus margin
+        p.busy(SpiClock::Clock1_5MHz.transfer_time(150));
+        clock.advance(Duration::from_micros(300));
+        p.wait();
+        assert_eq!(clock.sleeps(), vec![Duration::from_micros(600)]);
+
+        // Elapsed transfers do not wait
+        p.busy(Duration::from_micros(500));
+        clock.advance(Duration::from_micros(700));
+        p.wait();
+        assert_eq!(clock.sleeps(), vec![]);
+
+        // Device completion clears the busy period
+        p.busy(Duration::from_millis(10));
+        p.idle();
+        p.wait();
+        assert_eq!(clock.sleeps(), vec![]);
+
+        // Margin is configurable
+        p.set_margin(Duration::from_millis(1));
+        p.busy(Duration::from_millis(1));
+        assert_eq!(p.remaining(), Duration::from_millis(2));
+    }
+}
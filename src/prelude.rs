@@ -1,9 +1,18 @@
 
 pub use embedded_hal::spi::{Mode as SpiMode};
 
-pub use crate::{Cp2130, Device, Spi, InputPin, OutputPin, Error as Cp2130Error};
+pub use crate::{Cp2130, Device, Spi, SpiDevice, InputPin, OutputPin, GpioPort, ClockOut, EventCounter, EventRate, Error as Cp2130Error, ErrorKind as Cp2130ErrorKind, Op as Cp2130Op};
 
-pub use crate::device::{UsbOptions, GpioMode, GpioLevel, SpiConfig, SpiClock};
+pub use crate::device::{UsbOptions, TransferPolicy, GpioMode, GpioLevel, GpioLevels, GpioPin, SpiConfig, SpiDelays, DelayMask, CsMode, SpiClock, EventCounterMode, EventCount, ClockFrequency, RtrState, DeviceState, PinState};
+
+pub use crate::otp::{PromConfig, UsbConfig, UsbConfigMask, MAX_POWER_LIMIT_MA, PowerMode, TransferPriority, UsbString, LockByte, LockConfirmation, PinConfig, PinFunction};
+
+pub use crate::pacing::{Clock, SystemClock, Pacer};
+
+pub use crate::watch::{WatchOptions, GpioWatcher, GpioEvent, Edge, Debouncer, VcdWriter};
+
+#[cfg(feature = "async")]
+pub use crate::asynch::{AsyncCp2130, AsyncSpi, AsyncSpiDevice, AsyncInputPin, AsyncDelay};
 
 pub use crate::manager::{Manager, Filter};
 
@@ -0,0 +1,763 @@
+//! Pipelined bulk transfer engine for the CP2130
+//!
+//! This keeps multiple asynchronous libusb bulk transfers in flight so large
+//! reads and writes stream back-to-back, reusing a fixed pool of transfer buffers.
+//! Transfers are advanced from the libusb completion callback, so commands may be
+//! awaited by blocking on events (`Engine::run`) or driven by an event thread.
+//!
+//! Copyright 2019 Ryan Kurte
+
+use std::collections::VecDeque;
+use std::ptr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use libc::{c_int, c_uint, c_void, timeval};
+
+use rusb::ffi::{self, libusb_context, libusb_device_handle, libusb_transfer};
+use rusb::ffi::constants::*;
+
+use crate::{Error, Op};
+use crate::device::PACKET_SIZE;
+
+/// Default number of bulk transfers kept in flight per direction
+pub(crate) const DEFAULT_TRANSFER_DEPTH: usize = 4;
+
+/// Default size of each bulk transfer buffer in bytes
+pub(crate) const DEFAULT_TRANSFER_SIZE: usize = 4096;
+
+/// Interval for polling libusb events while awaiting completion
+const EVENT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Completion for a streamed command, called with the data read (or the error) once
+/// all transfers have been released. This runs on the thread handling libusb events.
+pub(crate) type Complete = Box<dyn FnOnce(Result<Vec<u8>, Error>) + Send>;
+
+/// Pre-allocated libusb transfer and buffer
+struct Slot {
+    transfer: *mut libusb_transfer,
+    buff: Vec<u8>,
+    done: bool,
+    busy: bool,
+    len: usize,
+}
+
+/// Source for bulk OUT data, a command header followed by data padded with zeros to `len`
+#[derive(Clone, Copy)]
+pub(crate) struct Source<'a> {
+    pub header: &'a [u8],
+    pub data: &'a [u8],
+    pub len: usize,
+}
+
+impl <'a> Source<'a> {
+    /// Copy the stream starting at `offset` into the provided buffer
+    pub(crate) fn fill(&self, offset: usize, buff: &mut [u8]) {
+        for (i, b) in buff.iter_mut().enumerate() {
+            let o = offset + i;
+            *b = match o < self.header.len() {
+                true => self.header[o],
+                false => self.data.get(o - self.header.len()).copied().unwrap_or(0),
+            };
+        }
+    }
+
+    /// Collect the full stream
+    pub(crate) fn to_vec(self) -> Vec<u8> {
+        let mut v = vec![0u8; self.len];
+        self.fill(0, &mut v);
+        v
+    }
+}
+
+/// Sink for bulk IN data, expecting `len` bytes with anything past the end of `buff` discarded
+pub(crate) struct Sink<'a> {
+    pub buff: &'a mut [u8],
+    pub len: usize,
+}
+
+/// Submission of filled libusb transfers, replaced in tests to run the engine without a device
+trait Submit: Send {
+    /// Submit a transfer, returning a libusb error code
+    fn submit(&mut self, t: *mut libusb_transfer) -> c_int;
+
+    /// Request cancellation of a submitted transfer
+    fn cancel(&mut self, t: *mut libusb_transfer);
+}
+
+/// Transfer submission via libusb
+struct Libusb;
+
+impl Submit for Libusb {
+    fn submit(&mut self, t: *mut libusb_transfer) -> c_int {
+        unsafe { ffi::libusb_submit_transfer(t) }
+    }
+
+    fn cancel(&mut self, t: *mut libusb_transfer) {
+        unsafe { ffi::libusb_cancel_transfer(t) };
+    }
+}
+
+/// Command being streamed through the transfer pool
+struct Stream {
+    out: Vec<u8>,
+    data: Vec<u8>,
+    in_len: usize,
+    timeout: c_uint,
+    out_sub: usize,
+    out_done: usize,
+    in_done: usize,
+    out_queue: VecDeque<usize>,
+    in_queue: VecDeque<usize>,
+    error: Option<Error>,
+    complete: Complete,
+}
+
+/// Transfer pool and stream state, shared with the libusb completion callback
+/// (which may run on any thread handling events for the context)
+struct State {
+    usb: Box<dyn Submit>,
+    handle: *mut libusb_device_handle,
+    write_ep: u8,
+    read_ep: u8,
+    write: Vec<Slot>,
+    read: Vec<Slot>,
+    progress: (usize, usize),
+    stream: Option<Stream>,
+}
+
+/// Transfer engine, owns a pool of libusb transfers for the read and write endpoints.
+///
+/// Commands are streamed by submitting transfers as pool slots become free, with the
+/// completion callback reaping transfers in order and submitting the next, so the
+/// pipeline is driven entirely by whichever thread handles libusb events.
+pub(crate) struct Engine {
+    ctx: *mut libusb_context,
+    state: Arc<Mutex<State>>,
+}
+
+// The raw handles outlive the engine (it is dropped before the owning device handle),
+// and transfers are only submitted or released with the state locked
+unsafe impl Send for Engine {}
+unsafe impl Send for State {}
+
+extern "system" fn transfer_complete(t: *mut libusb_transfer) {
+    let state = unsafe { &*((*t).user_data as *const Mutex<State>) };
+    state.lock().unwrap_or_else(PoisonError::into_inner).complete(t);
+}
+
+impl Engine {
+    /// Create a transfer engine with `depth` transfers of `size` bytes per direction
+    pub(crate) fn new(ctx: *mut libusb_context, handle: *mut libusb_device_handle, write_ep: u8, read_ep: u8, depth: usize, size: usize) -> Result<Self, Error> {
+        // Round buffers to whole packets so IN transfers can not overflow
+        let size = size.max(PACKET_SIZE).div_ceil(PACKET_SIZE) * PACKET_SIZE;
+        let state = State::new(Box::new(Libusb), handle, write_ep, read_ep, depth.max(1), size)?;
+
+        Ok(Engine{ctx, state: Arc::new(Mutex::new(state))})
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Total buffer capacity per direction in bytes
+    pub(crate) fn capacity(&self) -> usize {
+        self.state().write.iter().map(|s| s.buff.len()).sum()
+    }
+
+    /// Bytes written and read by the last command, including partial transfers on failure
+    pub(crate) fn progress(&self) -> (usize, usize) {
+        self.state().progress
+    }
+
+    /// Check whether a command is still in progress
+    pub(crate) fn busy(&self) -> bool {
+        self.state().stream.is_some()
+    }
+
+    /// Start streaming `out` and / or reading `in_len` bytes, calling `complete` once finished.
+    /// Transfers only progress while libusb events are handled (see `handle_events`).
+    pub(crate) fn start(&mut self, out: Vec<u8>, in_len: usize, timeout: Duration, complete: Complete) {
+        let ptr = Arc::as_ptr(&self.state) as *mut c_void;
+        self.state().start(ptr, out, in_len, timeout, complete);
+    }
+
+    /// Stream the source out and / or the sink in, returning the number of bytes read
+    pub(crate) fn run(&mut self, source: Option<Source>, sink: Option<Sink>, timeout: Duration) -> Result<usize, Error> {
+        let out = source.map(|s| s.to_vec()).unwrap_or_default();
+        let in_len = sink.as_ref().map(|s| s.len).unwrap_or(0);
+
+        let result = Arc::new(Mutex::new(None));
+        let r = result.clone();
+        self.start(out, in_len, timeout, Box::new(move |v| *r.lock().unwrap_or_else(PoisonError::into_inner) = Some(v)));
+
+        let data = loop {
+            if let Some(v) = result.lock().unwrap_or_else(PoisonError::into_inner).take() {
+                break v?;
+            }
+
+            // Failures are reported through the completion
+            let _ = self.handle_events();
+        };
+
+        // Copy into the sink, discarding anything past the end
+        if let Some(s) = sink {
+            let n = data.len().min(s.buff.len());
+            s.buff[..n].copy_from_slice(&data[..n]);
+        }
+
+        Ok(data.len())
+    }
+
+    /// Handle libusb events, returning after an event or the polling interval.
+    /// On failure any command in progress is cancelled and completed with the error.
+    pub(crate) fn handle_events(&mut self) -> Result<(), Error> {
+        if let Err(e) = Self::handle_events_ctx(self.ctx) {
+            self.abort(Error::usb(Op::Bulk, e));
+            return Err(Error::usb(Op::Bulk, e))
+        }
+
+        Ok(())
+    }
+
+    fn handle_events_ctx(ctx: *mut libusb_context) -> Result<(), rusb::Error> {
+        let tv = timeval{
+            tv_sec: EVENT_INTERVAL.as_secs() as _,
+            tv_usec: EVENT_INTERVAL.subsec_micros() as _,
+        };
+
+        let res = unsafe { ffi::libusb_handle_events_timeout_completed(ctx, &tv, ptr::null_mut()) };
+        match res {
+            0 | LIBUSB_ERROR_INTERRUPTED => Ok(()),
+            e => Err(usb_error(e)),
+        }
+    }
+
+    /// Cancel the command in progress and wait for its transfers to be released
+    fn abort(&mut self, e: Error) {
+        if !self.busy() {
+            return
+        }
+
+        self.state().fail(e);
+
+        while self.busy() {
+            if let Err(e) = Self::handle_events_ctx(self.ctx) {
+                error!("Error awaiting transfer cancellation: {:?}", e);
+                self.state().finish();
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for Engine {
+    fn drop(&mut self) {
+        self.abort(Error::usb(Op::Bulk, rusb::Error::Interrupted));
+
+        // Transfers still in flight can not be safely released, nor the state they reference
+        let s = self.state();
+        if s.write.iter().chain(s.read.iter()).any(|s| s.busy) {
+            std::mem::forget(self.state.clone());
+        }
+    }
+}
+
+impl State {
+    /// Create the transfer pool with `depth` transfers of `size` bytes per direction
+    fn new(usb: Box<dyn Submit>, handle: *mut libusb_device_handle, write_ep: u8, read_ep: u8, depth: usize, size: usize) -> Result<Self, Error> {
+        let mut s = State{usb, handle, write_ep, read_ep, write: Vec::with_capacity(depth), read: Vec::with_capacity(depth), progress: (0, 0), stream: None};
+
+        for _i in 0..depth {
+            s.write.push(Slot::new(size)?);
+            s.read.push(Slot::new(size)?);
+        }
+
+        Ok(s)
+    }
+
+    /// Start streaming a command, `ptr` is the shared state passed to the completion callback
+    fn start(&mut self, ptr: *mut c_void, out: Vec<u8>, in_len: usize, timeout: Duration, complete: Complete) {
+        // Transfers left in flight by a failed cancellation are never reused
+        if self.stream.is_some() || self.write.iter().chain(self.read.iter()).any(|s| s.busy) {
+            return complete(Err(Error::usb(Op::Bulk, rusb::Error::Busy)))
+        }
+
+        let timeout = timeout.as_millis().min(c_uint::MAX as u128) as c_uint;
+
+        self.progress = (0, 0);
+        self.stream = Some(Stream{
+            out, data: vec![0u8; in_len], in_len, timeout,
+            out_sub: 0, out_done: 0, in_done: 0,
+            out_queue: VecDeque::new(), in_queue: VecDeque::new(),
+            error: None, complete,
+        });
+
+        self.advance(ptr);
+    }
+
+    /// Handle completion of a transfer, advancing the stream
+    fn complete(&mut self, t: *mut libusb_transfer) {
+        let ptr = unsafe { (*t).user_data };
+
+        if let Some(s) = self.write.iter_mut().chain(self.read.iter_mut()).find(|s| s.transfer == t) {
+            s.done = true;
+        }
+
+        match self.stream.as_ref().map(|s| s.error.is_some()) {
+            Some(false) => self.advance(ptr),
+            Some(true) => self.release(),
+            // Late completion of a transfer abandoned by a failed cancellation
+            None => self.write.iter_mut().chain(self.read.iter_mut()).filter(|s| s.transfer == t).for_each(|s| s.busy = false),
+        }
+    }
+
+    /// Submit and reap transfers until the stream is waiting on the device,
+    /// `ptr` is the shared state passed to the completion callback
+    fn advance(&mut self, ptr: *mut c_void) {
+        match self.pump(ptr) {
+            Ok(true) => self.finish(),
+            Ok(false) => (),
+            Err(e) => self.fail(e),
+        }
+    }
+
+    /// Returns true once the stream has completed
+    fn pump(&mut self, ptr: *mut c_void) -> Result<bool, Error> {
+        let State{usb, handle, write_ep, read_ep, write, read, progress, stream} = self;
+        let s = match stream.as_mut() {
+            Some(s) => s,
+            None => return Ok(true),
+        };
+
+        loop {
+            // Submit writes while slots are available
+            while s.out_sub < s.out.len() {
+                let i = match write.iter().position(|s| !s.busy) {
+                    Some(i) => i,
+                    None => break,
+                };
+                let slot = &mut write[i];
+
+                let n = (s.out.len() - s.out_sub).min(slot.buff.len());
+                slot.buff[..n].copy_from_slice(&s.out[s.out_sub..][..n]);
+                slot.submit(usb.as_mut(), *handle, *write_ep, n, s.timeout, ptr)?;
+
+                s.out_queue.push_back(i);
+                s.out_sub += n;
+            }
+
+            // Submit reads for any data not already requested
+            loop {
+                let pending: usize = read.iter().filter(|s| s.busy).map(|s| s.len).sum();
+                let need = s.in_len.saturating_sub(s.in_done + pending);
+                if need == 0 {
+                    break;
+                }
+
+                let i = match read.iter().position(|s| !s.busy) {
+                    Some(i) => i,
+                    None => break,
+                };
+                let slot = &mut read[i];
+
+                let n = need.min(slot.buff.len());
+                slot.submit(usb.as_mut(), *handle, *read_ep, n, s.timeout, ptr)?;
+
+                s.in_queue.push_back(i);
+            }
+
+            if s.out_done >= s.out.len() && s.in_done >= s.in_len {
+                return Ok(true)
+            }
+
+            let mut reaped = false;
+
+            // Reap completed writes in order
+            while let Some(i) = s.out_queue.front().copied() {
+                let slot = &mut write[i];
+                if !slot.done {
+                    break;
+                }
+                s.out_queue.pop_front();
+
+                progress.0 += slot.actual();
+
+                let n = slot.complete()?;
+                Error::check_len(Op::Bulk, n, slot.len)?;
+
+                s.out_done += n;
+                reaped = true;
+            }
+
+            // Reap completed reads in order
+            while let Some(i) = s.in_queue.front().copied() {
+                let slot = &mut read[i];
+                if !slot.done {
+                    break;
+                }
+                s.in_queue.pop_front();
+
+                progress.1 += slot.actual();
+
+                let n = match slot.complete() {
+                    Ok(0) | Err(Error::Timeout(_)) => return Err(Error::ShortTransfer{op: Op::Bulk, actual: s.in_done, expected: s.in_len}),
+                    Ok(n) => n,
+                    Err(e) => return Err(e),
+                };
+
+                trace!("Bulk read complete (index: {}, len: {})", s.in_done, n);
+
+                // Copy into the stream data, discarding anything past the end
+                if s.in_done < s.data.len() {
+                    let m = n.min(s.data.len() - s.in_done);
+                    s.data[s.in_done..s.in_done+m].copy_from_slice(&slot.buff[..m]);
+                }
+
+                s.in_done += n;
+                reaped = true;
+            }
+
+            if !reaped {
+                return Ok(false)
+            }
+        }
+    }
+
+    /// Fail the stream, cancelling any transfers in flight
+    fn fail(&mut self, e: Error) {
+        if let Some(s) = self.stream.as_mut() {
+            s.error.get_or_insert(e);
+        }
+
+        let State{usb, write, read, ..} = self;
+        for s in write.iter().chain(read.iter()).filter(|s| s.busy && !s.done) {
+            usb.cancel(s.transfer);
+        }
+
+        self.release();
+    }
+
+    /// Release completed transfers for a failed stream, finishing once none remain in flight
+    fn release(&mut self) {
+        let State{write, read, progress, ..} = self;
+
+        for s in write.iter_mut().filter(|s| s.busy && s.done) {
+            progress.0 += s.actual();
+            s.busy = false;
+        }
+        for s in read.iter_mut().filter(|s| s.busy && s.done) {
+            progress.1 += s.actual();
+            s.busy = false;
+        }
+
+        if !self.write.iter().chain(self.read.iter()).any(|s| s.busy) {
+            self.finish();
+        }
+    }
+
+    /// Complete the stream, returning the data read or the error
+    fn finish(&mut self) {
+        let s = match self.stream.take() {
+            Some(s) => s,
+            None => return,
+        };
+
+        let res = match s.error {
+            Some(e) => Err(e),
+            None => {
+                let mut data = s.data;
+                data.truncate(s.in_done.min(s.in_len));
+                Ok(data)
+            },
+        };
+
+        (s.complete)(res)
+    }
+}
+
+impl Slot {
+    fn new(size: usize) -> Result<Self, Error> {
+        let transfer = unsafe { ffi::libusb_alloc_transfer(0) };
+        if transfer.is_null() {
+            return Err(Error::usb(Op::Bulk, rusb::Error::NoMem))
+        }
+
+        Ok(Slot{transfer, buff: vec![0u8; size], done: false, busy: false, len: 0})
+    }
+
+    /// Submit a transfer of `len` bytes, rounding IN requests up to whole packets
+    fn submit(&mut self, usb: &mut dyn Submit, handle: *mut libusb_device_handle, endpoint: u8, len: usize, timeout: c_uint, state: *mut c_void) -> Result<(), Error> {
+        let request = match endpoint & LIBUSB_ENDPOINT_DIR_MASK == LIBUSB_ENDPOINT_IN {
+            true => len.div_ceil(PACKET_SIZE) * PACKET_SIZE,
+            false => len,
+        }.min(self.buff.len());
+
+        self.done = false;
+
+        unsafe {
+            ffi::libusb_fill_bulk_transfer(
+                self.transfer,
+                handle,
+                endpoint,
+                self.buff.as_mut_ptr(),
+                request as c_int,
+                transfer_complete,
+                state,
+                timeout,
+            );
+        }
+
+        let res = usb.submit(self.transfer);
+
+        if res < 0 {
+            return Err(Error::usb(Op::Bulk, usb_error(res)))
+        }
+
+        self.busy = true;
+        self.len = len;
+
+        Ok(())
+    }
+
+    /// Number of bytes transferred by the last submission
+    fn actual(&self) -> usize {
+        unsafe { (*self.transfer).actual_length as usize }
+    }
+
+    /// Release a completed transfer, returning the number of bytes transferred
+    fn complete(&mut self) -> Result<usize, Error> {
+        self.busy = false;
+
+        let (status, n) = unsafe { ((*self.transfer).status, (*self.transfer).actual_length as usize) };
+
+        match status {
+            LIBUSB_TRANSFER_COMPLETED => Ok(n),
+            LIBUSB_TRANSFER_TIMED_OUT => Err(Error::usb(Op::Bulk, rusb::Error::Timeout)),
+            LIBUSB_TRANSFER_CANCELLED => Err(Error::usb(Op::Bulk, rusb::Error::Interrupted)),
+            LIBUSB_TRANSFER_STALL => Err(Error::usb(Op::Bulk, rusb::Error::Pipe)),
+            LIBUSB_TRANSFER_NO_DEVICE => Err(Error::usb(Op::Bulk, rusb::Error::NoDevice)),
+            LIBUSB_TRANSFER_OVERFLOW => Err(Error::usb(Op::Bulk, rusb::Error::Overflow)),
+            _ => Err(Error::usb(Op::Bulk, rusb::Error::Io)),
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        unsafe { ffi::libusb_free_transfer(self.transfer) };
+    }
+}
+
+/// Map libusb error codes to rusb errors
+fn usb_error(code: c_int) -> rusb::Error {
+    match code {
+        LIBUSB_ERROR_IO => rusb::Error::Io,
+        LIBUSB_ERROR_INVALID_PARAM => rusb::Error::InvalidParam,
+        LIBUSB_ERROR_ACCESS => rusb::Error::Access,
+        LIBUSB_ERROR_NO_DEVICE => rusb::Error::NoDevice,
+        LIBUSB_ERROR_NOT_FOUND => rusb::Error::NotFound,
+        LIBUSB_ERROR_BUSY => rusb::Error::Busy,
+        LIBUSB_ERROR_TIMEOUT => rusb::Error::Timeout,
+        LIBUSB_ERROR_OVERFLOW => rusb::Error::Overflow,
+        LIBUSB_ERROR_PIPE => rusb::Error::Pipe,
+        LIBUSB_ERROR_INTERRUPTED => rusb::Error::Interrupted,
+        LIBUSB_ERROR_NO_MEM => rusb::Error::NoMem,
+        LIBUSB_ERROR_NOT_SUPPORTED => rusb::Error::NotSupported,
+        _ => rusb::Error::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrorKind;
+
+    const WRITE_EP: u8 = 0x02;
+    const READ_EP: u8 = 0x81;
+
+    /// Outcome of a started command, set on completion
+    type Outcome = Arc<Mutex<Option<Result<Vec<u8>, Error>>>>;
+
+    /// Fake submission, recording submitted and cancelled transfers by address
+    #[derive(Clone, Default)]
+    struct FakeUsb {
+        submitted: Arc<Mutex<Vec<usize>>>,
+        cancelled: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl Submit for FakeUsb {
+        fn submit(&mut self, t: *mut libusb_transfer) -> c_int {
+            self.submitted.lock().unwrap().push(t as usize);
+            0
+        }
+
+        fn cancel(&mut self, t: *mut libusb_transfer) {
+            self.cancelled.lock().unwrap().push(t as usize);
+        }
+    }
+
+    impl FakeUsb {
+        /// Fetch and clear submitted transfers, with their endpoint and requested length
+        fn take(&self) -> Vec<(usize, u8, usize)> {
+            std::mem::take(&mut *self.submitted.lock().unwrap()).into_iter()
+                .map(|t| unsafe {
+                    let p = t as *mut libusb_transfer;
+                    (t, (*p).endpoint, (*p).length as usize)
+                })
+                .collect()
+        }
+    }
+
+    fn state(depth: usize) -> (Arc<Mutex<State>>, FakeUsb) {
+        let usb = FakeUsb::default();
+        let s = State::new(Box::new(usb.clone()), ptr::null_mut(), WRITE_EP, READ_EP, depth, PACKET_SIZE).unwrap();
+
+        (Arc::new(Mutex::new(s)), usb)
+    }
+
+    fn start(state: &Arc<Mutex<State>>, out: Vec<u8>, in_len: usize) -> Outcome {
+        let result = Outcome::default();
+        let r = result.clone();
+
+        let ptr = Arc::as_ptr(state) as *mut c_void;
+        state.lock().unwrap().start(ptr, out, in_len, Duration::from_secs(1), Box::new(move |v| *r.lock().unwrap() = Some(v)));
+
+        result
+    }
+
+    /// Complete a submitted transfer, IN transfers return `data`
+    fn done(state: &Arc<Mutex<State>>, t: usize, status: c_int, data: &[u8]) {
+        let p = t as *mut libusb_transfer;
+        unsafe {
+            (*p).status = status;
+            (*p).actual_length = match ((*p).endpoint == READ_EP, status) {
+                (true, _) => {
+                    ptr::copy_nonoverlapping(data.as_ptr(), (*p).buffer, data.len());
+                    data.len() as c_int
+                },
+                (false, LIBUSB_TRANSFER_COMPLETED) => (*p).length,
+                (false, _) => 0,
+            };
+        }
+
+        state.lock().unwrap().complete(p);
+    }
+
+    #[test]
+    fn source_fill() {
+        let s = Source{header: &[1, 2], data: &[3, 4, 5], len: 8};
+
+        // Data follows the header, padded with zeros to the stream length
+        assert_eq!(s.to_vec(), vec![1, 2, 3, 4, 5, 0, 0, 0]);
+
+        let mut buff = [0xFFu8; 3];
+        s.fill(1, &mut buff);
+        assert_eq!(buff, [2, 3, 4]);
+
+        s.fill(6, &mut buff);
+        assert_eq!(buff, [0, 0, 0]);
+    }
+
+    #[test]
+    fn pipelines_writes() {
+        let (state, usb) = state(2);
+        let out: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let result = start(&state, out, 0);
+
+        // The pool is filled with writes
+        let w = usb.take();
+        assert_eq!(w.iter().map(|(_t, ep, n)| (*ep, *n)).collect::<Vec<_>>(), vec![(WRITE_EP, 64), (WRITE_EP, 64)]);
+
+        // Writes are reaped in order, so slots are not reused until earlier writes complete
+        done(&state, w[1].0, LIBUSB_TRANSFER_COMPLETED, &[]);
+        assert!(usb.take().is_empty());
+
+        done(&state, w[0].0, LIBUSB_TRANSFER_COMPLETED, &[]);
+        let w = usb.take();
+        assert_eq!(w.iter().map(|(_t, _ep, n)| *n).collect::<Vec<_>>(), vec![64, 8]);
+        assert!(result.lock().unwrap().is_none());
+
+        for (t, _ep, _n) in w {
+            done(&state, t, LIBUSB_TRANSFER_COMPLETED, &[]);
+        }
+        assert_eq!(result.lock().unwrap().take().unwrap().unwrap(), vec![]);
+        assert_eq!(state.lock().unwrap().progress, (200, 0));
+    }
+
+    #[test]
+    fn streams_reads_alongside_writes() {
+        let (state, usb) = state(2);
+        let data: Vec<u8> = (0..150).map(|i| i as u8).collect();
+        let result = start(&state, vec![0u8; 8], data.len());
+
+        // Reads are queued alongside the command
+        let t = usb.take();
+        assert_eq!(t.iter().map(|(_t, ep, n)| (*ep, *n)).collect::<Vec<_>>(), vec![(WRITE_EP, 8), (READ_EP, 64), (READ_EP, 64)]);
+
+        // Completed reads are replaced, requesting whole packets for the remainder
+        done(&state, t[1].0, LIBUSB_TRANSFER_COMPLETED, &data[..64]);
+        let r = usb.take();
+        assert_eq!(r.iter().map(|(_t, ep, n)| (*ep, *n)).collect::<Vec<_>>(), vec![(READ_EP, 64)]);
+
+        done(&state, t[0].0, LIBUSB_TRANSFER_COMPLETED, &[]);
+        done(&state, t[2].0, LIBUSB_TRANSFER_COMPLETED, &data[64..128]);
+        assert!(result.lock().unwrap().is_none());
+
+        done(&state, r[0].0, LIBUSB_TRANSFER_COMPLETED, &data[128..]);
+        assert_eq!(result.lock().unwrap().take().unwrap().unwrap(), data);
+        assert_eq!(state.lock().unwrap().progress, (8, 150));
+    }
+
+    #[test]
+    fn failure_cancels_in_flight() {
+        let (state, usb) = state(2);
+        let result = start(&state, vec![0u8; 200], 0);
+        let w = usb.take();
+
+        // A failed transfer cancels those in flight, completing once all are released
+        done(&state, w[0].0, LIBUSB_TRANSFER_TIMED_OUT, &[]);
+        assert_eq!(*usb.cancelled.lock().unwrap(), vec![w[1].0]);
+        assert!(result.lock().unwrap().is_none());
+
+        done(&state, w[1].0, LIBUSB_TRANSFER_CANCELLED, &[]);
+        let e = result.lock().unwrap().take().unwrap().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Timeout);
+        assert_eq!(state.lock().unwrap().progress, (0, 0));
+
+        // The pool is reused by the next command
+        let _result = start(&state, vec![0u8; 8], 0);
+        assert_eq!(usb.take().len(), 1);
+    }
+
+    #[test]
+    fn empty_read_fails() {
+        let (state, usb) = state(1);
+        let result = start(&state, vec![0u8; 8], 4);
+        let t = usb.take();
+
+        // Zero length reads indicate the device has nothing more to send
+        done(&state, t[0].0, LIBUSB_TRANSFER_COMPLETED, &[]);
+        done(&state, t[1].0, LIBUSB_TRANSFER_COMPLETED, &[]);
+
+        let e = result.lock().unwrap().take().unwrap().unwrap_err();
+        assert!(matches!(e, Error::ShortTransfer{actual: 0, expected: 4, ..}));
+        assert_eq!(state.lock().unwrap().progress, (8, 0));
+    }
+
+    #[test]
+    fn busy_rejects_commands() {
+        let (state, usb) = state(1);
+        let first = start(&state, vec![0u8; 8], 0);
+        let w = usb.take();
+
+        // Commands are rejected while another is in progress
+        let second = start(&state, vec![0u8; 8], 0);
+        let e = second.lock().unwrap().take().unwrap().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Other);
+        assert!(usb.take().is_empty());
+
+        done(&state, w[0].0, LIBUSB_TRANSFER_COMPLETED, &[]);
+        assert!(first.lock().unwrap().take().unwrap().is_ok());
+    }
+}
@@ -0,0 +1,330 @@
+//! CP2130 USB transport
+//!
+//! This wraps the libusb device handle and bulk transfer engine behind a trait,
+//! so the device protocol logic can be exercised against a fake transport.
+//!
+//! Copyright 2019 Ryan Kurte
+
+use std::time::Duration;
+
+use rusb::{Device as UsbDevice, Context as UsbContext, DeviceHandle, Direction, UsbContext as _};
+
+use crate::Error;
+use crate::device::RequestType;
+use crate::transfer::{Engine, Source, Sink};
+#[cfg(feature = "async")]
+use crate::transfer::Complete;
+
+/// USB operations used by the device, implemented over libusb or faked for testing
+pub(crate) trait Transport: Send {
+    /// Issue a vendor IN control request, returning the number of bytes read
+    fn read_control(&mut self, request: u8, value: u16, index: u16, buff: &mut [u8], timeout: Duration) -> Result<usize, rusb::Error>;
+
+    /// Issue a vendor OUT control request, returning the number of bytes written
+    fn write_control(&mut self, request: u8, value: u16, index: u16, data: &[u8], timeout: Duration) -> Result<usize, rusb::Error>;
+
+    /// Read from the bulk IN endpoint
+    fn read_bulk(&mut self, buff: &mut [u8], timeout: Duration) -> Result<usize, rusb::Error>;
+
+    /// Clear a halt on the bulk endpoint for the provided direction
+    fn clear_halt(&mut self, direction: Direction) -> Result<(), rusb::Error>;
+
+    /// Reset the USB device
+    fn reset(&mut self) -> Result<(), rusb::Error>;
+
+    /// Stream the source out and / or the sink in, returning the number of bytes read
+    fn run(&mut self, source: Option<Source>, sink: Option<Sink>, timeout: Duration) -> Result<usize, Error>;
+
+    /// Start streaming `out` and / or reading `in_len` bytes without waiting, `complete` is
+    /// called (with any error) once finished, as transfers complete in `handle_events`
+    #[cfg(feature = "async")]
+    fn start(&mut self, out: Vec<u8>, in_len: usize, timeout: Duration, complete: Complete);
+
+    /// Handle transfer events, returning after an event or the polling interval
+    #[cfg(feature = "async")]
+    fn handle_events(&mut self) -> Result<(), Error>;
+
+    /// Check whether a started command is still in progress
+    #[cfg(feature = "async")]
+    fn busy(&self) -> bool;
+
+    /// Bytes written and read by the last run, including partial transfers on failure
+    fn progress(&self) -> (usize, usize);
+
+    /// Bulk buffer capacity per direction in bytes
+    fn capacity(&self) -> usize;
+}
+
+/// libusb transport, owning the device handle and bulk transfer engine
+pub(crate) struct UsbTransport {
+    // Declared first so in-flight transfers are released before the device handle
+    engine: Engine,
+    _device: UsbDevice<UsbContext>,
+    handle: DeviceHandle<UsbContext>,
+    write_ep: u8,
+    read_ep: u8,
+}
+
+impl UsbTransport {
+    /// Create a transport for an opened device, with `depth` bulk transfers of `size` bytes per direction
+    pub(crate) fn new(device: UsbDevice<UsbContext>, handle: DeviceHandle<UsbContext>, write_ep: u8, read_ep: u8, depth: usize, size: usize) -> Result<Self, Error> {
+        let engine = Engine::new(handle.context().as_raw(), handle.as_raw(), write_ep, read_ep, depth, size)?;
+
+        Ok(Self{engine, _device: device, handle, write_ep, read_ep})
+    }
+}
+
+impl Transport for UsbTransport {
+    fn read_control(&mut self, request: u8, value: u16, index: u16, buff: &mut [u8], timeout: Duration) -> Result<usize, rusb::Error> {
+        self.handle.read_control((RequestType::DEVICE_TO_HOST | RequestType::TYPE_VENDOR).bits(), request, value, index, buff, timeout)
+    }
+
+    fn write_control(&mut self, request: u8, value: u16, index: u16, data: &[u8], timeout: Duration) -> Result<usize, rusb::Error> {
+        self.handle.write_control((RequestType::HOST_TO_DEVICE | RequestType::TYPE_VENDOR).bits(), request, value, index, data, timeout)
+    }
+
+    fn read_bulk(&mut self, buff: &mut [u8], timeout: Duration) -> Result<usize, rusb::Error> {
+        self.handle.read_bulk(self.read_ep, buff, timeout)
+    }
+
+    fn clear_halt(&mut self, direction: Direction) -> Result<(), rusb::Error> {
+        match direction {
+            Direction::In => self.handle.clear_halt(self.read_ep),
+            Direction::Out => self.handle.clear_halt(self.write_ep),
+        }
+    }
+
+    fn reset(&mut self) -> Result<(), rusb::Error> {
+        self.handle.reset()
+    }
+
+    fn run(&mut self, source: Option<Source>, sink: Option<Sink>, timeout: Duration) -> Result<usize, Error> {
+        self.engine.run(source, sink, timeout)
+    }
+
+    #[cfg(feature = "async")]
+    fn start(&mut self, out: Vec<u8>, in_len: usize, timeout: Duration, complete: Complete) {
+        self.engine.start(out, in_len, timeout, complete)
+    }
+
+    #[cfg(feature = "async")]
+    fn handle_events(&mut self) -> Result<(), Error> {
+        self.engine.handle_events()
+    }
+
+    #[cfg(feature = "async")]
+    fn busy(&self) -> bool {
+        self.engine.busy()
+    }
+
+    fn progress(&self) -> (usize, usize) {
+        self.engine.progress()
+    }
+
+    fn capacity(&self) -> usize {
+        self.engine.capacity()
+    }
+}
+
+/// Fake transport recording requests and replaying scripted responses
+#[cfg(test)]
+pub(crate) mod fake {
+    use std::collections::{HashMap, VecDeque};
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::Op;
+
+    /// Request issued to the fake transport
+    #[derive(Debug, Clone, PartialEq)]
+    pub(crate) enum Request {
+        ControlRead(u8, u16, u16),
+        ControlWrite(u8, u16, u16, Vec<u8>),
+        /// Bulk run, with the data written and the number of bytes expected to be read
+        Bulk(Vec<u8>, usize),
+        ReadBulk(usize),
+        ClearHalt(Direction),
+        Reset,
+    }
+
+    /// Scripted bulk run failure, with the bytes written and read before failing
+    #[derive(Debug, Clone, PartialEq)]
+    pub(crate) struct BulkFault {
+        pub written: usize,
+        pub read: usize,
+        pub error: rusb::Error,
+    }
+
+    #[derive(Default)]
+    struct State {
+        requests: Vec<Request>,
+        responses: HashMap<u8, Vec<u8>>,
+        control_faults: VecDeque<rusb::Error>,
+        bulk_faults: VecDeque<Option<BulkFault>>,
+        read_bulk: VecDeque<Result<Vec<u8>, rusb::Error>>,
+        clear_halt_fault: Option<rusb::Error>,
+        progress: (usize, usize),
+        #[cfg(feature = "async")]
+        pending: Option<(Result<Vec<u8>, Error>, Complete)>,
+    }
+
+    /// Fake transport, clones share state so requests can be inspected once the transport is in use.
+    ///
+    /// Control reads return zeros unless a response is set, and bulk runs loop written data
+    /// (following the command header) back to the reader unless a fault is queued.
+    /// Started commands complete on the next call to `handle_events`.
+    #[derive(Clone, Default)]
+    pub(crate) struct FakeTransport {
+        state: Arc<Mutex<State>>,
+    }
+
+    impl FakeTransport {
+        /// Fetch and clear the recorded requests
+        pub fn take_requests(&self) -> Vec<Request> {
+            std::mem::take(&mut self.state.lock().unwrap().requests)
+        }
+
+        /// Set the response returned for a control read request
+        pub fn set_response(&self, request: u8, data: &[u8]) {
+            self.state.lock().unwrap().responses.insert(request, data.to_vec());
+        }
+
+        /// Fail the next control request
+        pub fn push_control_fault(&self, e: rusb::Error) {
+            self.state.lock().unwrap().control_faults.push_back(e);
+        }
+
+        /// Queue the outcome of the next bulk run, `None` for success
+        pub fn push_bulk(&self, fault: Option<BulkFault>) {
+            self.state.lock().unwrap().bulk_faults.push_back(fault);
+        }
+
+        /// Queue the result of the next bulk IN read, reads time out when none are queued
+        pub fn push_read_bulk(&self, r: Result<Vec<u8>, rusb::Error>) {
+            self.state.lock().unwrap().read_bulk.push_back(r);
+        }
+
+        /// Fail endpoint halt clearing
+        pub fn set_clear_halt_fault(&self, e: Option<rusb::Error>) {
+            self.state.lock().unwrap().clear_halt_fault = e;
+        }
+
+        /// Record a bulk command, returning the looped back data or the queued fault
+        fn bulk(&self, out: Vec<u8>, in_len: usize) -> Result<Vec<u8>, Error> {
+            let mut s = self.state.lock().unwrap();
+            s.requests.push(Request::Bulk(out.clone(), in_len));
+
+            if let Some(Some(f)) = s.bulk_faults.pop_front() {
+                s.progress = (f.written, f.read);
+                return Err(Error::usb(Op::Bulk, f.error))
+            }
+
+            s.progress = (out.len(), in_len);
+
+            // Loop back written data, excluding the command header
+            let data = out.get(8..).unwrap_or(&[]);
+            Ok((0..in_len).map(|i| data.get(i).copied().unwrap_or(0)).collect())
+        }
+    }
+
+    impl Transport for FakeTransport {
+        fn read_control(&mut self, request: u8, value: u16, index: u16, buff: &mut [u8], _timeout: Duration) -> Result<usize, rusb::Error> {
+            let mut s = self.state.lock().unwrap();
+            s.requests.push(Request::ControlRead(request, value, index));
+
+            if let Some(e) = s.control_faults.pop_front() {
+                return Err(e)
+            }
+
+            match s.responses.get(&request) {
+                Some(r) => {
+                    let n = r.len().min(buff.len());
+                    buff[..n].copy_from_slice(&r[..n]);
+                    Ok(n)
+                },
+                None => {
+                    buff.iter_mut().for_each(|b| *b = 0);
+                    Ok(buff.len())
+                },
+            }
+        }
+
+        fn write_control(&mut self, request: u8, value: u16, index: u16, data: &[u8], _timeout: Duration) -> Result<usize, rusb::Error> {
+            let mut s = self.state.lock().unwrap();
+            s.requests.push(Request::ControlWrite(request, value, index, data.to_vec()));
+
+            match s.control_faults.pop_front() {
+                Some(e) => Err(e),
+                None => Ok(data.len()),
+            }
+        }
+
+        fn read_bulk(&mut self, buff: &mut [u8], _timeout: Duration) -> Result<usize, rusb::Error> {
+            let mut s = self.state.lock().unwrap();
+            s.requests.push(Request::ReadBulk(buff.len()));
+
+            let r = s.read_bulk.pop_front().unwrap_or(Err(rusb::Error::Timeout))?;
+            let n = r.len().min(buff.len());
+            buff[..n].copy_from_slice(&r[..n]);
+
+            Ok(n)
+        }
+
+        fn clear_halt(&mut self, direction: Direction) -> Result<(), rusb::Error> {
+            let mut s = self.state.lock().unwrap();
+            s.requests.push(Request::ClearHalt(direction));
+
+            match s.clear_halt_fault {
+                Some(e) => Err(e),
+                None => Ok(()),
+            }
+        }
+
+        fn reset(&mut self) -> Result<(), rusb::Error> {
+            self.state.lock().unwrap().requests.push(Request::Reset);
+            Ok(())
+        }
+
+        fn run(&mut self, source: Option<Source>, sink: Option<Sink>, _timeout: Duration) -> Result<usize, Error> {
+            let out = source.map(|s| s.to_vec()).unwrap_or_default();
+            let in_len = sink.as_ref().map(|s| s.len).unwrap_or(0);
+
+            let data = self.bulk(out, in_len)?;
+            if let Some(sink) = sink {
+                let n = data.len().min(sink.buff.len());
+                sink.buff[..n].copy_from_slice(&data[..n]);
+            }
+
+            Ok(data.len())
+        }
+
+        #[cfg(feature = "async")]
+        fn start(&mut self, out: Vec<u8>, in_len: usize, _timeout: Duration, complete: Complete) {
+            let res = self.bulk(out, in_len);
+            self.state.lock().unwrap().pending = Some((res, complete));
+        }
+
+        #[cfg(feature = "async")]
+        fn handle_events(&mut self) -> Result<(), Error> {
+            let pending = self.state.lock().unwrap().pending.take();
+            if let Some((res, complete)) = pending {
+                complete(res);
+            }
+
+            Ok(())
+        }
+
+        #[cfg(feature = "async")]
+        fn busy(&self) -> bool {
+            self.state.lock().unwrap().pending.is_some()
+        }
+
+        fn progress(&self) -> (usize, usize) {
+            self.state.lock().unwrap().progress
+        }
+
+        fn capacity(&self) -> usize {
+            crate::transfer::DEFAULT_TRANSFER_DEPTH * crate::transfer::DEFAULT_TRANSFER_SIZE
+        }
+    }
+}
@@ -0,0 +1,413 @@
+//! CP2130 GPIO watcher
+//!
+//! The CP2130 has no GPIO interrupts, so pins are monitored by polling the
+//! GPIO values from a background thread. Samples are debounced and delivered
+//! as timestamped edge events via a callback or channel, and may be captured
+//! to a VCD file for viewing in PulseView / GTKWave.
+//!
+//! Copyright 2019 Ryan Kurte
+
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::{Cp2130, Error, GpioLevels, GpioPin, lock};
+use crate::device::Inner;
+use crate::otp::GPIO_COUNT;
+
+/// Default GPIO watcher sampling interval
+pub const DEFAULT_WATCH_INTERVAL: Duration = Duration::from_millis(10);
+
+/// GPIO watcher options
+#[derive(Debug, PartialEq, Clone)]
+pub struct WatchOptions {
+    /// Pins to monitor
+    pub pins: GpioLevels,
+    /// Interval between samples
+    pub interval: Duration,
+    /// Number of consecutive samples required to accept a level change (1 disables debouncing)
+    pub debounce: u32,
+}
+
+impl WatchOptions {
+    /// Check the options are usable, the interval must be non-zero and representable as a deadline
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.interval.is_zero() || Instant::now().checked_add(self.interval).is_none() {
+            return Err(Error::InvalidInterval(self.interval))
+        }
+
+        Ok(())
+    }
+
+    /// Maximum expected delay between an edge and it being reported, as debounced
+    /// edges are timestamped at their first sample (includes one interval of margin)
+    pub fn latency(&self) -> Duration {
+        self.interval.saturating_mul(self.debounce.max(1))
+    }
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        Self {
+            pins: GpioLevels::all(),
+            interval: DEFAULT_WATCH_INTERVAL,
+            debounce: 1,
+        }
+    }
+}
+
+/// GPIO edge direction
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Edge {
+    Rising,
+    Falling,
+}
+
+/// GPIO edge event
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct GpioEvent {
+    /// Pin on which the edge occurred
+    pub pin: GpioPin,
+    /// Edge direction
+    pub edge: Edge,
+    /// Time of the first sample at the new level, since the watcher was started
+    pub time: Duration,
+}
+
+/// Debouncer tracks stable pin levels from a series of samples, producing edge events
+#[derive(Debug, Clone)]
+pub struct Debouncer {
+    pins: GpioLevels,
+    debounce: u32,
+    levels: GpioLevels,
+    pending: [Option<(Duration, u32)>; GPIO_COUNT],
+}
+
+impl Debouncer {
+    /// Create a new debouncer for the provided pins, starting from the initial levels
+    pub fn new(pins: GpioLevels, debounce: u32, initial: GpioLevels) -> Self {
+        Self{pins, debounce: debounce.max(1), levels: initial & pins, pending: [None; GPIO_COUNT]}
+    }
+
+    /// Fetch the current debounced levels
+    pub fn levels(&self) -> GpioLevels {
+        self.levels
+    }
+
+    /// Update with a new sample taken at time `t`, returning any accepted edges in time order
+    pub fn update(&mut self, sample: GpioLevels, t: Duration) -> Vec<GpioEvent> {
+        let mut events = vec![];
+
+        for p in GpioPin::all().iter().copied() {
+            let mask = GpioLevels::from(p);
+            if !self.pins.contains(mask) {
+                continue;
+            }
+
+            let pending = &mut self.pending[p as usize];
+
+            let level = sample.contains(mask);
+            if level == self.levels.contains(mask) {
+                *pending = None;
+                continue;
+            }
+
+            let (since, count) = match pending {
+                Some((since, count)) => (*since, *count + 1),
+                None => (t, 1),
+            };
+
+            if count < self.debounce {
+                *pending = Some((since, count));
+                continue;
+            }
+
+            *pending = None;
+            self.levels.set(mask, level);
+
+            let edge = match level {
+                true => Edge::Rising,
+                false => Edge::Falling,
+            };
+            events.push(GpioEvent{pin: p, edge, time: since});
+        }
+
+        events.sort_by_key(|e| e.time);
+
+        events
+    }
+}
+
+/// GpioWatcher polls GPIO pins from a background thread, stopping on drop
+pub struct GpioWatcher {
+    initial: GpioLevels,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<Result<(), Error>>>,
+}
+
+impl GpioWatcher {
+    fn new<F>(inner: Arc<Mutex<Inner>>, opts: WatchOptions, mut f: F) -> Result<Self, Error>
+    where
+        F: FnMut(GpioEvent) + Send + 'static,
+    {
+        opts.validate()?;
+
+        // Take the initial sample up-front so failures are reported immediately
+        let start = Instant::now();
+        let initial = lock(&inner)?.get_gpio_values()? & opts.pins;
+
+        let mut debouncer = Debouncer::new(opts.pins, opts.debounce, initial);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let s = stop.clone();
+
+        let thread = std::thread::spawn(move || {
+            let mut next = start;
+
+            while !s.load(Ordering::Relaxed) {
+                // Wait for the next sample, skipping any missed samples
+                next = next.checked_add(opts.interval).ok_or(Error::InvalidInterval(opts.interval))?;
+                let now = Instant::now();
+                match next.checked_duration_since(now) {
+                    Some(d) => std::thread::sleep(d),
+                    None => next = now,
+                }
+
+                let t = Instant::now();
+                let sample = lock(&inner)?.get_gpio_values()?;
+
+                for e in debouncer.update(sample, t.duration_since(start)) {
+                    f(e);
+                }
+            }
+
+            Ok(())
+        });
+
+        Ok(Self{initial, stop, thread: Some(thread)})
+    }
+
+    /// Fetch the watched pin levels when the watcher was started
+    pub fn initial_levels(&self) -> GpioLevels {
+        self.initial
+    }
+
+    /// Check whether the watcher is still running
+    pub fn is_running(&self) -> bool {
+        self.thread.as_ref().map(|t| !t.is_finished()).unwrap_or(false)
+    }
+
+    /// Stop the watcher, returning any error that stopped polling
+    pub fn stop(mut self) -> Result<(), Error> {
+        self.join()
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        self.stop.store(true, Ordering::Relaxed);
+
+        match self.thread.take().map(|t| t.join()) {
+            Some(Ok(r)) => r,
+            Some(Err(_)) => Err(Error::Poisoned),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for GpioWatcher {
+    fn drop(&mut self) {
+        if let Err(e) = self.join() {
+            warn!("GPIO watcher failed: {:?}", e);
+        }
+    }
+}
+
+impl Cp2130 {
+    /// Start a GPIO watcher, calling `f` for each debounced edge
+    pub fn watch<F>(&self, opts: WatchOptions, f: F) -> Result<GpioWatcher, Error>
+    where
+        F: FnMut(GpioEvent) + Send + 'static,
+    {
+        GpioWatcher::new(self.inner.clone(), opts, f)
+    }
+
+    /// Start a GPIO watcher, delivering debounced edges via a channel
+    pub fn watch_channel(&self, opts: WatchOptions) -> Result<(GpioWatcher, mpsc::Receiver<GpioEvent>), Error> {
+        let (tx, rx) = mpsc::channel();
+
+        let w = self.watch(opts, move |e| {
+            let _ = tx.send(e);
+        })?;
+
+        Ok((w, rx))
+    }
+}
+
+/// VCD (value change dump) writer for GPIO events.
+///
+/// Debounced edges may be reported out of time order, so events are buffered for
+/// the configured window (see `set_window`) and written sorted. Buffered events
+/// are written on `flush` or `into_inner`.
+pub struct VcdWriter<W: Write> {
+    w: W,
+    last: Duration,
+    latest: Duration,
+    window: Duration,
+    pending: Vec<GpioEvent>,
+}
+
+impl <W: Write> VcdWriter<W> {
+    /// Write the VCD header for the provided pins and initial levels, with a 1 us timescale
+    pub fn new(mut w: W, pins: GpioLevels, initial: GpioLevels) -> std::io::Result<Self> {
+        writeln!(w, "$version cp2130-util {} $end", env!("CARGO_PKG_VERSION"))?;
+        writeln!(w, "$timescale 1us $end")?;
+        writeln!(w, "$scope module cp2130 $end")?;
+        for p in Self::pins(pins) {
+            writeln!(w, "$var wire 1 {} gpio{} $end", Self::id(p), p.index())?;
+        }
+        writeln!(w, "$upscope $end")?;
+        writeln!(w, "$enddefinitions $end")?;
+
+        writeln!(w, "#0")?;
+        writeln!(w, "$dumpvars")?;
+        for p in Self::pins(pins) {
+            writeln!(w, "{}{}", initial.contains(p.into()) as u8, Self::id(p))?;
+        }
+        writeln!(w, "$end")?;
+
+        w.flush()?;
+
+        Ok(Self{w, last: Duration::from_secs(0), latest: Duration::from_secs(0), window: Duration::from_secs(0), pending: vec![]})
+    }
+
+    /// Set the window over which events are buffered and re-ordered,
+    /// this should be at least `WatchOptions::latency` for debounced captures
+    pub fn set_window(&mut self, window: Duration) {
+        self.window = window;
+    }
+
+    /// Add a GPIO event, writing any buffered events that can no longer be preceded
+    /// by a later event, flushing so the capture is usable if interrupted
+    pub fn event(&mut self, e: &GpioEvent) -> std::io::Result<()> {
+        self.pending.push(*e);
+        self.latest = self.latest.max(e.time);
+
+        // Later events are at most one window older than the latest event
+        self.write_pending(Some(self.latest.saturating_sub(self.window)))
+    }
+
+    /// Write all buffered events
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.write_pending(None)
+    }
+
+    /// Write all buffered events, then fetch the underlying writer
+    pub fn into_inner(mut self) -> std::io::Result<W> {
+        self.flush()?;
+        Ok(self.w)
+    }
+
+    /// Write buffered events up to (and including) `until` in time order
+    fn write_pending(&mut self, until: Option<Duration>) -> std::io::Result<()> {
+        self.pending.sort_by_key(|e| e.time);
+
+        let n = match until {
+            Some(t) => self.pending.iter().take_while(|e| e.time <= t).count(),
+            None => self.pending.len(),
+        };
+
+        for e in self.pending.drain(..n) {
+            // Timestamps must not decrease
+            if e.time < self.last {
+                warn!("VCD event for GPIO{} out of order by {:?}, increase the window", e.pin.index(), self.last - e.time);
+            } else if e.time > self.last {
+                writeln!(self.w, "#{}", e.time.as_micros())?;
+                self.last = e.time;
+            }
+
+            let v = match e.edge {
+                Edge::Rising => 1,
+                Edge::Falling => 0,
+            };
+            writeln!(self.w, "{}{}", v, Self::id(e.pin))?;
+        }
+
+        self.w.flush()
+    }
+
+    fn pins(pins: GpioLevels) -> impl Iterator<Item=GpioPin> {
+        GpioPin::all().iter().copied().filter(move |p| pins.contains((*p).into()))
+    }
+
+    fn id(p: GpioPin) -> char {
+        (b'!' + p.index()) as char
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debouncer() {
+        let mut d = Debouncer::new(GpioLevels::GPIO_3, 2, GpioLevels::empty());
+
+        // Changes must be stable for the debounce count, and glitches are ignored
+        assert_eq!(d.update(GpioLevels::GPIO_3, Duration::from_millis(10)), vec![]);
+        assert_eq!(d.update(GpioLevels::empty(), Duration::from_millis(20)), vec![]);
+        assert_eq!(d.update(GpioLevels::GPIO_3, Duration::from_millis(30)), vec![]);
+
+        // Accepted edges are timestamped at the first sample of the new level
+        assert_eq!(d.update(GpioLevels::GPIO_3, Duration::from_millis(40)), vec![
+            GpioEvent{pin: GpioPin::Gpio3, edge: Edge::Rising, time: Duration::from_millis(30)},
+        ]);
+        assert_eq!(d.levels(), GpioLevels::GPIO_3);
+
+        // Unwatched pins are ignored
+        assert_eq!(d.update(GpioLevels::GPIO_3 | GpioLevels::GPIO_4, Duration::from_millis(50)), vec![]);
+        assert_eq!(d.update(GpioLevels::GPIO_3 | GpioLevels::GPIO_4, Duration::from_millis(60)), vec![]);
+    }
+
+    #[test]
+    fn vcd_writer() {
+        let pins = GpioLevels::GPIO_0 | GpioLevels::GPIO_2;
+        let mut w = VcdWriter::new(vec![], pins, GpioLevels::GPIO_2).unwrap();
+
+        w.event(&GpioEvent{pin: GpioPin::Gpio0, edge: Edge::Rising, time: Duration::from_micros(1500)}).unwrap();
+        w.event(&GpioEvent{pin: GpioPin::Gpio2, edge: Edge::Falling, time: Duration::from_micros(1500)}).unwrap();
+
+        let s = String::from_utf8(w.into_inner().unwrap()).unwrap();
+        assert!(s.contains("$var wire 1 ! gpio0 $end\n$var wire 1 # gpio2 $end\n"));
+        assert!(s.contains("$dumpvars\n0!\n1#\n$end\n"));
+        assert!(s.ends_with("#1500\n1!\n0#\n"));
+    }
+
+    #[test]
+    fn vcd_writer_reorders_debounced_events() {
+        let opts = WatchOptions{pins: GpioLevels::GPIO_0 | GpioLevels::GPIO_1, interval: Duration::from_millis(10), debounce: 3};
+        assert_eq!(opts.latency(), Duration::from_millis(30));
+
+        let mut w = VcdWriter::new(vec![], opts.pins, GpioLevels::empty()).unwrap();
+        w.set_window(opts.latency());
+
+        // Debounced edges may be reported after later edges on other pins
+        w.event(&GpioEvent{pin: GpioPin::Gpio0, edge: Edge::Rising, time: Duration::from_millis(40)}).unwrap();
+        w.event(&GpioEvent{pin: GpioPin::Gpio1, edge: Edge::Rising, time: Duration::from_millis(20)}).unwrap();
+        w.event(&GpioEvent{pin: GpioPin::Gpio0, edge: Edge::Falling, time: Duration::from_millis(100)}).unwrap();
+
+        let s = String::from_utf8(w.into_inner().unwrap()).unwrap();
+        assert!(s.ends_with("#20000\n1\"\n#40000\n1!\n#100000\n0!\n"));
+    }
+
+    #[test]
+    fn watch_options_validate() {
+        assert!(WatchOptions::default().validate().is_ok());
+
+        // Zero intervals would busy-loop and unrepresentable deadlines would overflow
+        for interval in [Duration::from_secs(0), Duration::MAX] {
+            let opts = WatchOptions{interval, ..Default::default()};
+            assert!(matches!(opts.validate(), Err(Error::InvalidInterval(i)) if i == interval));
+        }
+    }
+}